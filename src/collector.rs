@@ -0,0 +1,76 @@
+use core::time::Duration;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::JoinHandle;
+
+use crate::hazard::HazardStore;
+use crate::{Domain, Thread};
+
+/// A background thread reclaiming the retired pointers of a domain, started by
+/// `Domain::spawn_collector`. Dropping the handle shuts the collector down as `shutdown` does.
+#[derive(Debug)]
+pub struct CollectorHandle {
+    stop: Arc<(Mutex<bool>, Condvar)>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl CollectorHandle {
+    pub(crate) fn spawn<S: HazardStore>(
+        domain: &'static Domain<S>,
+        period: Duration,
+        threshold: usize,
+    ) -> Self {
+        let stop = Arc::new((Mutex::new(false), Condvar::new()));
+        let thread = {
+            let stop = stop.clone();
+            std::thread::spawn(move || {
+                let (stopped, cvar) = &*stop;
+                let mut guard = stopped.lock().unwrap();
+                while !*guard {
+                    guard = cvar.wait_timeout(guard, period).unwrap().0;
+                    if *guard || domain.num_garbages() < threshold {
+                        continue;
+                    }
+                    drop(guard);
+                    collect(domain);
+                    guard = stopped.lock().unwrap();
+                }
+                drop(guard);
+                // Reclaim what was flushed up to the shutdown.
+                collect(domain);
+            })
+        };
+        Self {
+            stop,
+            thread: Some(thread),
+        }
+    }
+
+    /// Stop the collector after a last reclamation and wait for it to exit.
+    pub fn shutdown(mut self) {
+        self.stop_and_join();
+    }
+
+    fn stop_and_join(&mut self) {
+        let Some(thread) = self.thread.take() else {
+            return;
+        };
+        let (stopped, cvar) = &*self.stop;
+        *stopped.lock().unwrap() = true;
+        cvar.notify_one();
+        if thread.join().is_err() && !std::thread::panicking() {
+            panic!("the collector thread panicked");
+        }
+    }
+}
+
+impl Drop for CollectorHandle {
+    fn drop(&mut self) {
+        self.stop_and_join();
+    }
+}
+
+/// Attach a `Thread` to reclaim the retired pointers of `domain`, and detach it.
+fn collect<S: HazardStore>(domain: &Domain<S>) {
+    let mut thread = Thread::new(domain);
+    thread.do_reclamation();
+}
@@ -1,79 +1,1101 @@
-use core::sync::atomic::{AtomicUsize, Ordering};
+#[cfg(feature = "retire-notify")]
+use alloc::collections::BTreeMap;
+#[cfg(feature = "debug-checks")]
+use alloc::collections::BTreeSet;
+use core::time::Duration;
+use core::{iter, mem, ptr};
+use std::sync::Mutex;
+use std::time::Instant;
 
-use crossbeam_utils::CachePadded;
+use crossbeam_utils::{Backoff, CachePadded};
 use rustc_hash::FxHashSet;
 
-use crate::hazard::ThreadRecords;
-use crate::retire::RetiredList;
+use crate::collector::CollectorHandle;
+#[cfg(feature = "metrics")]
+use crate::exporter::MetricHandles;
+use crate::hazard::{
+    GlobalRecordAllocator, HazardArray, HazardStore, RecordAllocator, ThreadRecords,
+};
+use crate::retire::{free_batch, Retired, RetiredList};
+use crate::sync::{self, AtomicBool, AtomicPtr, AtomicUsize, Ordering};
 use crate::thread::Thread;
+#[cfg(feature = "chrome-trace")]
+use crate::trace::Tracer;
+#[cfg(feature = "debug-checks")]
+use crate::tracking::AllocationTracker;
 
-#[derive(Debug)]
-pub struct Domain {
-    pub(crate) threads: CachePadded<ThreadRecords>,
+/// Reclamation cadence of the threads of a domain. Each interval is counted in the number of
+/// retirements and successful unlinks of a thread.
+// The callbacks are compared by address, which is enough to tell a restored snapshot.
+#[allow(unpredictable_function_pointer_comparisons)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DomainConfig {
+    /// Interval of invalidating the unlinked nodes (HP++).
+    pub invalidation_interval: usize,
+    /// Interval of flushing the thread-local retired pointers to the domain.
+    pub flush_interval: usize,
+    /// Interval of reclaiming the retired pointers of the domain.
+    pub collect_interval: usize,
+    /// Maximum total size of the hazard arrays of all threads, in bytes. A hazard array can't grow
+    /// beyond it to serve `HazardPointer::try_new`, but `HazardPointer::new` ignores it.
+    pub max_hazard_bytes: usize,
+    /// Free the reclaimable pointers in the order they were retired (oldest first), e.g. for
+    /// reasoning about object lifetimes. Retirements of different threads are ordered by the time
+    /// they are flushed to the domain. This costs a sort in each reclamation.
+    pub fifo_reclamation: bool,
+    /// Number of times reading the epoch for invalidation retries with the light barrier while
+    /// the epoch keeps advancing, before it yields the thread between the retries.
+    pub epoch_read_spins: usize,
+    /// Reclaim all unguarded retired pointers of the domain when its last thread is dropped, so
+    /// that a dynamically-scoped domain doesn't keep garbage while no thread uses it. Those in
+    /// their grace period are freed too, with up to three reclamations.
+    pub reclaim_on_detach: bool,
+    /// Free a retired pointer only after it is unguarded and the epoch has advanced twice since it
+    /// was flushed to the domain, i.e. it survived at least one reclamation. Correct hazard
+    /// pointer usage doesn't need it: it is a safety margin for developing or integrating a data
+    /// structure that is not fully trusted, at the cost of keeping garbage longer.
+    pub two_epoch_grace: bool,
+    /// High watermark of the retired pointers flushed to the domain (`Domain::num_garbages`).
+    /// While it is exceeded, each retirement flushes and reclaims right away, regardless of
+    /// `collect_interval`, e.g. to bound the garbage of a bursty producer. While the garbage stays
+    /// guarded above it, this costs a heavy barrier per retirement.
+    pub max_garbage: usize,
+    /// Number of low bits of pointers ignored when matching retired pointers against the
+    /// protected ones, for structures that keep tags in them, e.g. a mark bit. Then protecting a
+    /// tagged pointer protects its untagged retirement, and vice versa. It is fixed when the
+    /// domain is created and can't be changed by `Domain::apply_config`.
+    pub tag_bits: u32,
+    /// Called with the number of retired pointers each time a batch of them is flushed to the
+    /// domain, e.g. to emit events to a metrics system.
+    pub on_retire: Option<fn(count: usize)>,
+    /// Called at the end of each reclamation with the number of retired pointers freed (or left
+    /// to the pending frees) and of those retained.
+    pub on_reclaim: Option<fn(freed: usize, retained: usize)>,
+    /// How the heavy barrier of reclamation is paired with the protections of readers. It is
+    /// fixed when the domain is created and can't be changed by `Domain::apply_config`.
+    pub barrier_strategy: BarrierStrategy,
+}
+
+/// The implementation of the barriers of a domain.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum BarrierStrategy {
+    /// `membarrier::heavy()` by the reclaimer, which makes the readers' side a compiler fence.
+    #[default]
+    Membarrier,
+    /// `SeqCst` fences on both sides, for platforms or sandboxes where the membarrier syscall is
+    /// unavailable, at the cost of a fence in each protection.
+    SeqCst,
+}
+
+impl DomainConfig {
+    pub const fn new() -> Self {
+        Self {
+            invalidation_interval: 32,
+            flush_interval: 64,
+            collect_interval: 128,
+            max_hazard_bytes: usize::MAX,
+            fifo_reclamation: false,
+            epoch_read_spins: usize::MAX,
+            reclaim_on_detach: false,
+            two_epoch_grace: false,
+            max_garbage: usize::MAX,
+            tag_bits: 0,
+            on_retire: None,
+            on_reclaim: None,
+            barrier_strategy: BarrierStrategy::Membarrier,
+        }
+    }
+
+    /// The mask of the address bits compared by reclamation, as set by `tag_bits`.
+    #[inline]
+    pub(crate) const fn tag_mask(&self) -> usize {
+        !((1 << self.tag_bits) - 1)
+    }
+
+    /// Panics if the intervals can't work together: they must be positive, and the retired
+    /// pointers must be flushed at least as often as they are collected, or a collection may find
+    /// none of the thread's own.
+    const fn validate(&self) {
+        assert!(
+            self.invalidation_interval > 0 && self.flush_interval > 0 && self.collect_interval > 0,
+            "the intervals of `DomainConfig` must be positive"
+        );
+        assert!(
+            self.flush_interval <= self.collect_interval,
+            "`flush_interval` of `DomainConfig` must not exceed `collect_interval`"
+        );
+        assert!(
+            self.tag_bits < usize::BITS,
+            "`tag_bits` of `DomainConfig` must be less than the pointer width"
+        );
+    }
+}
+
+/// A snapshot of the reclamation counters of a domain, taken by `Domain::stats`. Each field is
+/// read separately, so they may be slightly inconsistent with each other under concurrency.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct DomainStats {
+    /// Number of retired pointers flushed to the domain so far.
+    pub retired: usize,
+    /// Number of retired pointers freed by reclamations so far.
+    pub freed: usize,
+    /// Number of heavy barriers issued by reclamations so far.
+    pub reclamation_barriers: usize,
+    /// `Domain::num_garbages`.
+    pub num_garbages: usize,
+    /// `Domain::hazard_bytes`.
+    pub hazard_bytes: usize,
+    /// `Domain::last_guarded_count`.
+    pub last_guarded_count: usize,
+}
+
+impl Default for DomainConfig {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A reclamation domain, whose threads keep their hazard pointers in stores of type `S`.
+pub struct Domain<S: HazardStore = HazardArray> {
+    pub(crate) threads: CachePadded<ThreadRecords<S>>,
     pub(crate) barrier: CachePadded<EpochBarrier>,
     pub(crate) retireds: CachePadded<RetiredList>,
     pub(crate) num_garbages: CachePadded<AtomicUsize>,
+    /// Number of `Thread`s of the domain that are not dropped yet.
+    pub(crate) attached: AtomicUsize,
+    /// Size of the guarded set of the last reclamation.
+    pub(crate) last_guarded_count: AtomicUsize,
+    /// Counters of `DomainStats`.
+    pub(crate) retired_total: AtomicUsize,
+    pub(crate) freed_total: AtomicUsize,
+    pub(crate) reclamation_barriers: AtomicUsize,
+    /// Next retirement sequence number, used if `fifo_reclamation` is enabled.
+    pub(crate) retire_seq: AtomicUsize,
+    pub(crate) allocator: &'static dyn RecordAllocator,
+    /// The scan slot of `reclaim_all`, which has no thread record of its own (see
+    /// `ThreadRecord::scan_hazard`).
+    scan_hazard: AtomicPtr<u8>,
+    /// Serializes `reclaim_all`, which has a single `scan_hazard`.
+    scan_lock: Mutex<()>,
+    /// The configuration applied last by `apply_config`, or null if it is `initial_config`.
+    config: CachePadded<AtomicPtr<DomainConfig>>,
+    initial_config: DomainConfig,
+    /// The configurations applied by `apply_config`, kept alive for the references given out by
+    /// `config`. They are boxed so that their addresses don't change when the `Vec` grows.
+    #[allow(clippy::vec_box)]
+    applied_configs: Mutex<Vec<Box<DomainConfig>>>,
+    free_filter: Option<FreeFilter>,
+    #[cfg(feature = "debug-checks")]
+    allocation_tracker: Option<&'static dyn AllocationTracker>,
+    /// The addresses retired to this domain and not freed yet, to detect double retirements.
+    #[cfg(feature = "debug-checks")]
+    retired_addrs: Mutex<BTreeSet<usize>>,
+    dry_run: AtomicBool,
+    pub(crate) dry_run_freeable: AtomicUsize,
+    #[cfg(feature = "retire-notify")]
+    notifications: Mutex<RetireNotifications>,
+    #[cfg(feature = "chrome-trace")]
+    pub(crate) tracer: Tracer,
+    #[cfg(feature = "metrics")]
+    metrics: std::sync::OnceLock<MetricHandles>,
+}
+
+type FreeFilter = Box<dyn Fn(*mut u8) -> bool + Send + Sync>;
+
+/// Callbacks registered by `HazardPointer::protect_with_notify`, by the address they wait for.
+#[cfg(feature = "retire-notify")]
+struct RetireNotifications {
+    next_id: usize,
+    callbacks: BTreeMap<usize, Vec<(usize, RetireCallback)>>,
 }
 
+#[cfg(feature = "retire-notify")]
+type RetireCallback = Box<dyn FnOnce() + Send>;
+
 impl Domain {
     pub const fn new() -> Self {
+        Self::with_config(DomainConfig::new())
+    }
+
+    /// Create a domain with the reclamation cadence `config`.
+    ///
+    /// # Panics
+    /// If an interval of `config` is zero, or `flush_interval` exceeds `collect_interval`.
+    pub const fn with_config(config: DomainConfig) -> Self {
+        config.validate();
+        Self::build(config, &GlobalRecordAllocator)
+    }
+
+    /// Create a domain with the thread records and hazard arrays for `threads` threads allocated
+    /// up front, so that the first `threads` concurrent `Thread::new` calls don't allocate them.
+    /// See `with_hazard_store_capacity` for other stores and allocators.
+    pub fn with_capacity(threads: usize) -> Self {
+        Self::with_hazard_store_capacity(DomainConfig::new(), &GlobalRecordAllocator, threads)
+    }
+
+    /// Create a domain whose thread records and hazard arrays are allocated by `allocator`.
+    pub const fn with_allocator(allocator: &'static dyn RecordAllocator) -> Self {
+        Self::build(DomainConfig::new(), allocator)
+    }
+}
+
+impl<S: HazardStore> Domain<S> {
+    /// Create a domain with the reclamation cadence `config`, whose threads keep their hazard
+    /// pointers in stores of type `S` allocated by `allocator`.
+    ///
+    /// # Panics
+    /// As `with_config`, if `config` is invalid.
+    pub const fn with_hazard_store(
+        config: DomainConfig,
+        allocator: &'static dyn RecordAllocator,
+    ) -> Self {
+        config.validate();
+        Self::build(config, allocator)
+    }
+
+    /// `with_hazard_store` with the thread records and hazard stores for `threads` threads
+    /// allocated up front, as in `Domain::with_capacity`.
+    ///
+    /// Only the records are reserved. The retired pointers are buffered by each `Thread` and
+    /// pushed to the domain in batches that are only allocated when flushed, so there is
+    /// nothing else to reserve.
+    pub fn with_hazard_store_capacity(
+        config: DomainConfig,
+        allocator: &'static dyn RecordAllocator,
+        threads: usize,
+    ) -> Self {
+        let domain = Self::with_hazard_store(config, allocator);
+        domain.threads.reserve(threads, domain.allocator);
+        domain
+    }
+
+    const fn build(config: DomainConfig, allocator: &'static dyn RecordAllocator) -> Self {
         Self {
             threads: CachePadded::new(ThreadRecords::new()),
-            barrier: CachePadded::new(EpochBarrier(AtomicUsize::new(0))),
+            barrier: CachePadded::new(EpochBarrier::new(config.barrier_strategy)),
             retireds: CachePadded::new(RetiredList::new()),
             num_garbages: CachePadded::new(AtomicUsize::new(0)),
+            attached: AtomicUsize::new(0),
+            last_guarded_count: AtomicUsize::new(0),
+            retired_total: AtomicUsize::new(0),
+            freed_total: AtomicUsize::new(0),
+            reclamation_barriers: AtomicUsize::new(0),
+            retire_seq: AtomicUsize::new(0),
+            allocator,
+            config: CachePadded::new(AtomicPtr::new(ptr::null_mut())),
+            initial_config: config,
+            applied_configs: Mutex::new(Vec::new()),
+            scan_hazard: AtomicPtr::new(ptr::null_mut()),
+            scan_lock: Mutex::new(()),
+            free_filter: None,
+            #[cfg(feature = "debug-checks")]
+            allocation_tracker: None,
+            #[cfg(feature = "debug-checks")]
+            retired_addrs: Mutex::new(BTreeSet::new()),
+            dry_run: AtomicBool::new(false),
+            dry_run_freeable: AtomicUsize::new(0),
+            #[cfg(feature = "retire-notify")]
+            notifications: Mutex::new(RetireNotifications {
+                next_id: 0,
+                callbacks: BTreeMap::new(),
+            }),
+            #[cfg(feature = "chrome-trace")]
+            tracer: Tracer::new(),
+            #[cfg(feature = "metrics")]
+            metrics: std::sync::OnceLock::new(),
         }
     }
 
-    pub fn collect_guarded_ptrs<'domain>(
+    pub fn config(&self) -> &DomainConfig {
+        let config = self.config.load(Ordering::Acquire);
+        if config.is_null() {
+            &self.initial_config
+        } else {
+            unsafe { &*config }
+        }
+    }
+
+    /// A copy of the current configuration, e.g. to restore it by `apply_config` later.
+    pub fn config_snapshot(&self) -> DomainConfig {
+        *self.config()
+    }
+
+    /// Replace the configuration of this domain while its threads are running. Each thread picks
+    /// up the new values on its next retirement or unlink, before checking the intervals. An
+    /// operation in flight completes with the values the thread had read.
+    ///
+    /// Each applied configuration is kept until the domain is dropped, so this is meant for
+    /// occasional changes such as experimenting with reclamation policies, not for every
+    /// operation.
+    ///
+    /// # Panics
+    /// As `with_config`, if `config` is invalid, or if it changes `tag_bits` or
+    /// `barrier_strategy`.
+    pub fn apply_config(&self, config: DomainConfig) {
+        config.validate();
+        assert!(
+            config.barrier_strategy == self.barrier.strategy,
+            "`barrier_strategy` of a domain can't be changed by `apply_config`"
+        );
+        assert!(
+            config.tag_bits == self.config().tag_bits,
+            "`tag_bits` of a domain can't be changed by `apply_config`"
+        );
+        let mut applied = self.applied_configs.lock().unwrap();
+        let config = Box::new(config);
+        self.config
+            .store(&*config as *const _ as *mut _, Ordering::Release);
+        applied.push(config);
+    }
+
+    /// The address of the current configuration, which changes on each `apply_config`.
+    #[inline]
+    pub(crate) fn config_version(&self) -> *const DomainConfig {
+        self.config.load(Ordering::Relaxed)
+    }
+
+    /// Set a filter consulted before freeing each unguarded retired pointer. If it returns `false`,
+    /// the pointer is kept retired and checked again in the next reclamation. It is only consulted
+    /// for the pointers retired or unlinked by the user, not for the hazard arrays replaced by the
+    /// threads or the calls of `Thread::defer`.
+    ///
+    /// A filter that never releases a pointer leaks it until the domain is dropped, which frees
+    /// all retired pointers regardless of the filter.
+    pub fn set_free_filter(&mut self, filter: impl Fn(*mut u8) -> bool + Send + Sync + 'static) {
+        self.free_filter = Some(Box::new(filter));
+    }
+
+    /// Check that every pointer retired or unlinked to this domain is allocated according to
+    /// `tracker`, e.g. the `TrackingAllocator` installed as the global allocator.
+    #[cfg(feature = "debug-checks")]
+    pub fn set_allocation_tracker(&mut self, tracker: &'static dyn AllocationTracker) {
+        self.allocation_tracker = Some(tracker);
+    }
+
+    #[cfg(feature = "debug-checks")]
+    #[track_caller]
+    pub(crate) fn check_allocated(&self, ptr: *mut u8) {
+        if let Some(tracker) = self.allocation_tracker {
+            assert!(
+                tracker.is_allocated(ptr),
+                "{:p} is retired but not allocated by the tracked allocator",
+                ptr
+            );
+        }
+    }
+
+    #[cfg(feature = "debug-checks")]
+    #[track_caller]
+    pub(crate) fn check_retired_once<T>(&self, ptr: *mut T) {
+        // Boxes of zero-sized types share a dangling address.
+        if core::mem::size_of::<T>() == 0 {
+            return;
+        }
+        assert!(
+            self.retired_addrs.lock().unwrap().insert(ptr as usize),
+            "{:p} is retired again before it is freed",
+            ptr
+        );
+    }
+
+    /// Forget the addresses of `retireds`, which are about to be freed, so that the allocator
+    /// may hand them out again.
+    #[cfg(feature = "debug-checks")]
+    pub(crate) fn forget_retired(&self, retireds: &[Retired]) {
+        let mut addrs = self.retired_addrs.lock().unwrap();
+        for retired in retireds {
+            addrs.remove(&(retired.ptr as usize));
+        }
+    }
+
+    /// Write the reclamation events of this domain to `writer` as a JSON array of Chrome Trace
+    /// Events, which Perfetto and `chrome://tracing` load: a complete event for each reclamation
+    /// pass and heavy barrier, and an instant event for each flush of retired pointers. Events carry
+    /// the counts of pointers involved in `args`, and their timestamps are relative to this call.
+    ///
+    /// A writer set before is finished as by `stop_trace`. Errors of the writer are ignored.
+    #[cfg(feature = "chrome-trace")]
+    pub fn set_trace_writer(&self, writer: impl std::io::Write + Send + 'static) {
+        self.tracer.start(Box::new(writer));
+    }
+
+    /// Stop writing reclamation events and terminate the JSON array. Dropping the domain does the
+    /// same.
+    #[cfg(feature = "chrome-trace")]
+    pub fn stop_trace(&self) {
+        self.tracer.stop();
+    }
+
+    /// The handles of the metrics reported to the `metrics` facade.
+    #[cfg(feature = "metrics")]
+    pub(crate) fn metrics(&self) -> &MetricHandles {
+        self.metrics.get_or_init(MetricHandles::register)
+    }
+
+    /// `EpochBarrier::barrier`, traced and counted if enabled.
+    #[inline]
+    pub(crate) fn heavy_barrier(&self) {
+        #[cfg(feature = "chrome-trace")]
+        let begin = self.tracer.begin();
+        #[cfg(feature = "metrics")]
+        self.metrics().membarriers_total.increment(1);
+        self.barrier.barrier();
+        #[cfg(feature = "chrome-trace")]
+        self.tracer.complete("barrier", begin, &[]);
+    }
+
+    /// Push `retireds` to the retired pointers of this domain, as a thread configured with `config`
+    /// flushes them.
+    pub(crate) fn flush(&self, mut retireds: Vec<Retired>, config: &DomainConfig) {
+        if config.fifo_reclamation {
+            let seq = self.retire_seq.fetch_add(retireds.len(), Ordering::Relaxed);
+            for (i, retired) in retireds.iter_mut().enumerate() {
+                retired.seq = seq.wrapping_add(i);
+            }
+        }
+        if config.two_epoch_grace {
+            // Ordered after the retirements, so that the two barriers advancing the epoch from it
+            // are ordered after them as well.
+            let epoch = self.barrier.read(config.epoch_read_spins);
+            for retired in &mut retireds {
+                retired.epoch = epoch;
+            }
+        }
+        self.num_garbages
+            .fetch_add(retireds.len(), Ordering::AcqRel);
+        self.retired_total
+            .fetch_add(retireds.len(), Ordering::Relaxed);
+        #[cfg(feature = "metrics")]
+        self.metrics().garbage.set(self.num_garbages() as f64);
+        #[cfg(feature = "chrome-trace")]
+        self.tracer.instant("flush", &[("retired", retireds.len())]);
+        if let Some(on_retire) = config.on_retire {
+            on_retire(retireds.len());
+        }
+        self.retireds.push(retireds)
+    }
+
+    /// Retire `ptr` directly to this domain, without a `Thread`, e.g. by a thread that unlinked it
+    /// from a structure shared with others but doesn't reclaim itself. It is freed by the next
+    /// reclamation of any thread of this domain that finds it unguarded. Unlike
+    /// `Thread::retire`, this never starts a reclamation.
+    ///
+    /// # Safety
+    /// The same as `Thread::retire`. As `ptr` is freed by another thread, `T` must be `Send`.
+    pub unsafe fn retire_global<T: Send>(&self, ptr: *mut T) {
+        self.retire_direct(ptr)
+    }
+
+    /// `retire_global` without the `Send` bound, for `LocalThread::retire`, which retires for the
+    /// current thread like `Thread::retire`.
+    pub(crate) unsafe fn retire_direct<T>(&self, ptr: *mut T) {
+        #[cfg(feature = "debug-checks")]
+        {
+            self.check_allocated(ptr.cast());
+            self.check_retired_once(ptr);
+        }
+        #[cfg(feature = "retire-notify")]
+        self.notify_retired([ptr.cast()]);
+        self.flush(vec![Retired::new(ptr)], self.config());
+    }
+
+    #[inline]
+    pub(crate) fn may_free(&self, retired: &Retired) -> bool {
+        match &self.free_filter {
+            Some(filter) if !retired.internal => filter(retired.ptr),
+            _ => true,
+        }
+    }
+
+    /// Replace the contents of `guarded` with the pointers protected by the threads of this
+    /// domain, reusing its allocation. The tags of the pointers (`tag_bits`) are cleared.
+    pub fn collect_guarded_ptrs(
         &self,
-        reclaimer: &mut Thread<'domain>,
-    ) -> FxHashSet<*mut u8> {
-        self.threads
+        reclaimer: &mut Thread<'_, S>,
+        guarded: &mut FxHashSet<*mut u8>,
+    ) {
+        self.collect_guarded_ptrs_masked(
+            &reclaimer.hazards.scan_hazard,
+            guarded,
+            self.config().tag_mask(),
+        );
+    }
+
+    /// `collect_guarded_ptrs` with the `tag_mask` that the caller matches the retired pointers
+    /// with, read once for the whole reclamation, protecting the scanned stores with the scan
+    /// slot `slot`.
+    pub(crate) fn collect_guarded_ptrs_masked(
+        &self,
+        slot: &AtomicPtr<u8>,
+        guarded: &mut FxHashSet<*mut u8>,
+        tag_mask: usize,
+    ) {
+        guarded.clear();
+        for thread in self.threads.iter() {
+            thread.collect_protected(slot, &self.barrier, guarded, tag_mask);
+        }
+        // The stores scanned by the other reclamations.
+        let scanned = self.threads.iter().map(|thread| &thread.scan_hazard);
+        guarded.extend(
+            scanned
+                .chain(iter::once(&self.scan_hazard))
+                .map(|slot| slot.load(Ordering::Acquire))
+                .filter(|ptr| !ptr.is_null())
+                .map(|ptr| (ptr as usize & tag_mask) as *mut u8),
+        );
+    }
+
+    /// Number of `Thread`s of this domain that are not dropped yet.
+    pub fn attached_threads(&self) -> usize {
+        self.attached.load(Ordering::Relaxed)
+    }
+
+    /// Recompute `num_garbages` from the retired pointers in the domain, e.g. to heal a drift of
+    /// the counter in a long-lived domain, and return it. The retired pointers of `reclaimer` are
+    /// flushed first. Those buffered by the other threads are not in the domain yet, so they are
+    /// not counted, as in `num_garbages`.
+    ///
+    /// This must be called at a quiescent point: a concurrent flush or reclamation may be lost.
+    pub fn recount_garbage(&self, reclaimer: &mut Thread<'_, S>) -> usize {
+        assert!(ptr::eq(self, reclaimer.domain));
+        reclaimer.flush_retireds();
+        let retireds = self.retireds.pop_all();
+        let count = retireds.len();
+        if count > 0 {
+            self.retireds.push(retireds);
+        }
+        self.num_garbages.store(count, Ordering::Relaxed);
+        count
+    }
+
+    /// The current epoch of this domain. Everything that happened before this call is ordered
+    /// before the heavy barriers that advance the epoch from it.
+    pub fn current_epoch(&self) -> Epoch {
+        self.barrier.light();
+        Epoch(self.barrier.epoch.load(Ordering::Acquire))
+    }
+
+    /// Whether a grace period has elapsed since `since`, i.e. the epoch has advanced twice, as
+    /// `Thread::defer` and `two_epoch_grace` wait for: two heavy barriers, which act on all
+    /// threads, are ordered after the observation of `since`.
+    pub fn grace_period_elapsed(&self, since: Epoch) -> bool {
+        EpochBarrier::check(since.0, self.barrier.current())
+    }
+
+    /// The retired pointers in this domain that more than `threshold_passes` reclamations have
+    /// kept, with the number of those reclamations, e.g. to find objects kept by a leaked
+    /// protection. Pointers buffered by threads are not in the domain yet, so they are not listed.
+    ///
+    /// This must be called at a quiescent point, as `recount_garbage`.
+    #[cfg(feature = "debug-checks")]
+    pub fn long_lived_garbage(&self, threshold_passes: usize) -> Vec<(*mut u8, usize)> {
+        let retireds = self.retireds.pop_all();
+        let long_lived = retireds
             .iter()
-            .flat_map(|thread| thread.iter(reclaimer))
-            .collect()
+            .filter(|r| r.survived > threshold_passes)
+            .map(|r| (r.ptr, r.survived))
+            .collect();
+        if !retireds.is_empty() {
+            self.retireds.push(retireds);
+        }
+        long_lived
+    }
+
+    /// Number of distinct pointers protected by hazard pointers in the last reclamation of this
+    /// domain, which kept the retired ones among them from being freed. If it stays large while
+    /// little is freed, threads hold their protections for too long.
+    pub fn last_guarded_count(&self) -> usize {
+        self.last_guarded_count.load(Ordering::Relaxed)
+    }
+
+    /// Read the reclamation counters of this domain, e.g. before and after a workload to compute
+    /// the number of pointers freed per barrier.
+    pub fn stats(&self) -> DomainStats {
+        DomainStats {
+            retired: self.retired_total.load(Ordering::Relaxed),
+            freed: self.freed_total.load(Ordering::Relaxed),
+            reclamation_barriers: self.reclamation_barriers.load(Ordering::Relaxed),
+            num_garbages: self.num_garbages(),
+            hazard_bytes: self.hazard_bytes(),
+            last_guarded_count: self.last_guarded_count(),
+        }
     }
 
     pub fn num_garbages(&self) -> usize {
         self.num_garbages.load(Ordering::Relaxed)
     }
+
+    /// Enable or disable the dry-run mode, in which reclamation computes which retired pointers
+    /// are freeable but keeps all of them retired, e.g. to profile reclamation separately from the
+    /// allocator.
+    ///
+    /// This leaks memory by design: nothing is freed until the mode is disabled or the domain is
+    /// dropped.
+    pub fn set_dry_run(&self, enabled: bool) {
+        self.dry_run.store(enabled, Ordering::Relaxed);
+    }
+
+    #[inline]
+    pub(crate) fn is_dry_run(&self) -> bool {
+        self.dry_run.load(Ordering::Relaxed)
+    }
+
+    /// The number of retired pointers that reclamation would have freed in dry-run mode so far.
+    pub fn dry_run_freeable(&self) -> usize {
+        self.dry_run_freeable.load(Ordering::Relaxed)
+    }
+
+    /// Move the retired pointers of this domain to `other`, instead of freeing them when this
+    /// domain is dropped, so that they are reclaimed under the protections of `other`'s threads.
+    ///
+    /// As this consumes the domain, all of its threads are already dropped, and pointers protected
+    /// only by them are no longer protected. Threads that still access the pointers must protect
+    /// them with hazard pointers of `other`.
+    ///
+    /// The epochs of the domains are unrelated, so the grace periods of the moved pointers
+    /// (`two_epoch_grace`, `Thread::defer`) restart in `other`.
+    pub fn merge_into(self, other: &Domain<S>) {
+        let mut retireds = self.retireds.pop_all();
+        if retireds.is_empty() {
+            return;
+        }
+        let epoch = other.barrier.read(other.config().epoch_read_spins);
+        for retired in &mut retireds {
+            retired.epoch = epoch;
+        }
+        self.num_garbages
+            .fetch_sub(retireds.len(), Ordering::AcqRel);
+        other
+            .num_garbages
+            .fetch_add(retireds.len(), Ordering::AcqRel);
+        other.retireds.push(retireds);
+    }
+
+    /// Reclaim the retired pointers of this domain without a `Thread`, e.g. to check for leaks at
+    /// the end of a test or before a long idle period. The guarded pointers are kept retired.
+    /// Those buffered by the threads are not in the domain yet, so they are kept as well. Returns
+    /// the number of pointers freed.
+    ///
+    /// Pointers in their grace period (`two_epoch_grace`, `Thread::defer`) are freed too, with
+    /// the barriers it takes. Concurrent calls are serialized, but the reclamations of the
+    /// threads run alongside.
+    pub fn reclaim_all(&self) -> usize {
+        let _scan = self.scan_lock.lock().unwrap();
+        let config = *self.config();
+        let tag_mask = config.tag_mask();
+        let mut guarded = FxHashSet::default();
+        let mut freed = 0;
+        for _ in 0..3 {
+            let Some(mut reclamation) = Reclamation::begin(self, &config) else {
+                break;
+            };
+            self.collect_guarded_ptrs_masked(&self.scan_hazard, &mut guarded, tag_mask);
+            let to_free = reclamation.sift(&guarded, tag_mask, &config);
+            #[cfg(feature = "debug-checks")]
+            self.forget_retired(&to_free);
+            unsafe { free_batch(&mut [], to_free, config.fifo_reclamation) };
+            freed += reclamation.finish(guarded.len(), &config);
+            if self.num_garbages() == 0 {
+                break;
+            }
+        }
+        freed
+    }
+
+    /// Complete the reclamation of the pointers that threads of this domain unlinked but left
+    /// uninvalidated because they panicked, and make their records available again. The pointers
+    /// are invalidated and retired by `reclaimer`. Returns the number of recovered pointers.
+    ///
+    /// A leaked `Thread` (e.g. `mem::forget`) is not dropped, so its pointers can't be recovered.
+    pub fn reclaim_abandoned(&self, reclaimer: &mut Thread<'_, S>) -> usize {
+        assert!(ptr::eq(self, reclaimer.domain));
+        let mut count = 0;
+        for record in self.threads.iter() {
+            let abandoned = record.abandoned.swap(ptr::null_mut(), Ordering::Acquire);
+            if abandoned.is_null() {
+                continue;
+            }
+            let unlinkeds = unsafe { Box::from_raw(abandoned) };
+            let mut retireds = Vec::new();
+            for unlinked in *unlinkeds {
+                retireds.append(&mut unlinked.do_invalidation());
+            }
+            // The frontier stays protected until the invalidation is visible to all threads.
+            self.heavy_barrier();
+            let store = unsafe { &*record.hazptrs.load(Ordering::Relaxed) };
+            for idx in 0..store.len() {
+                unsafe { store.clear(idx) };
+            }
+            record.active_hazards.store(0, Ordering::Relaxed);
+            self.threads.release(record);
+
+            count += retireds.len();
+            for retired in retireds {
+                unsafe { reclaimer.push_retired(retired) };
+            }
+        }
+        count
+    }
+
+    /// Register `callback` to be called when `ptr` is retired, returning its id.
+    #[cfg(feature = "retire-notify")]
+    pub(crate) fn register_notify(&self, ptr: *mut u8, callback: RetireCallback) -> usize {
+        let mut notifications = self.notifications.lock().unwrap();
+        let id = notifications.next_id;
+        notifications.next_id += 1;
+        notifications
+            .callbacks
+            .entry(ptr as usize)
+            .or_default()
+            .push((id, callback));
+        id
+    }
+
+    /// Unregister the callback `id` of `ptr`, or `None` if it has already been called.
+    #[cfg(feature = "retire-notify")]
+    pub(crate) fn unregister_notify(&self, ptr: *mut u8, id: usize) -> Option<RetireCallback> {
+        let mut notifications = self.notifications.lock().unwrap();
+        let callbacks = notifications.callbacks.get_mut(&(ptr as usize))?;
+        let pos = callbacks.iter().position(|(i, _)| *i == id)?;
+        let (_, callback) = callbacks.swap_remove(pos);
+        if callbacks.is_empty() {
+            notifications.callbacks.remove(&(ptr as usize));
+        }
+        Some(callback)
+    }
+
+    /// Call the callbacks registered for the newly retired pointers.
+    #[cfg(feature = "retire-notify")]
+    pub(crate) fn notify_retired(&self, ptrs: impl IntoIterator<Item = *mut u8>) {
+        let mut fired = Vec::new();
+        {
+            let mut notifications = self.notifications.lock().unwrap();
+            if notifications.callbacks.is_empty() {
+                return;
+            }
+            for ptr in ptrs {
+                if let Some(callbacks) = notifications.callbacks.remove(&(ptr as usize)) {
+                    fired.extend(callbacks);
+                }
+            }
+        }
+        // Called without the lock, so that they can register new callbacks.
+        for (_, callback) in fired {
+            callback();
+        }
+    }
+
+    /// Total size of the hazard arrays currently used by the threads, in bytes.
+    pub fn hazard_bytes(&self) -> usize {
+        self.threads.hazard_bytes.load(Ordering::Relaxed)
+    }
+
+    /// Whether no thread of this domain currently holds a hazard pointer. Unlike `quiesce`, this
+    /// doesn't wait, and the result may be stale as soon as it returns.
+    pub fn is_quiescent(&self) -> bool {
+        self.threads
+            .iter()
+            .map(|t| t.active_hazards.load(Ordering::Acquire))
+            .sum::<usize>()
+            == 0
+    }
+
+    /// Waits until no thread of this domain holds a hazard pointer, or until `timeout` elapses.
+    /// Returns `true` if all threads were observed to be clear.
+    ///
+    /// This only observes the threads: it cannot force them to release their hazard pointers, and
+    /// they may acquire new ones right after this returns. Hazard pointers of the caller's own
+    /// `Thread` are counted as well, so they should be dropped before calling this.
+    pub fn quiesce(&self, timeout: Duration) -> bool {
+        let deadline = Instant::now().checked_add(timeout);
+        let backoff = Backoff::new();
+        loop {
+            if self.is_quiescent() {
+                return true;
+            }
+            if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                return false;
+            }
+            backoff.snooze();
+        }
+    }
+
+    /// Start a background thread that reclaims the retired pointers of this domain, so that
+    /// worker threads can leave reclamation to it by setting `collect_interval` to `usize::MAX`.
+    /// Every `period`, if at least `threshold` retired pointers are flushed to the domain, it
+    /// attaches a `Thread`, reclaims, and detaches it.
+    ///
+    /// Only flushed pointers are visible to the collector, so workers still flush every
+    /// `flush_interval` retirements (or `max_local_retired` buffered ones).
+    pub fn spawn_collector(&'static self, period: Duration, threshold: usize) -> CollectorHandle {
+        CollectorHandle::spawn(self, period, threshold)
+    }
+}
+
+impl<S: HazardStore> core::fmt::Debug for Domain<S> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("Domain")
+            .field("threads", &self.threads)
+            .field("barrier", &self.barrier)
+            .field("retireds", &self.retireds)
+            .field("num_garbages", &self.num_garbages)
+            .field("attached", &self.attached)
+            .field("last_guarded_count", &self.last_guarded_count)
+            .field("config", self.config())
+            .field("free_filter", &self.free_filter.is_some())
+            .field("dry_run", &self.dry_run)
+            .finish()
+    }
+}
+
+impl Default for Domain {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
-impl Drop for Domain {
+impl<S: HazardStore> Drop for Domain<S> {
     fn drop(&mut self) {
         for t in self.threads.iter() {
-            assert!(t.available.load(Ordering::Relaxed))
+            let abandoned = t.abandoned.load(Ordering::Relaxed);
+            if abandoned.is_null() {
+                assert!(t.available.load(Ordering::Relaxed));
+                continue;
+            }
+            for unlinked in *unsafe { Box::from_raw(abandoned) } {
+                unsafe { unlinked.free() };
+            }
         }
         let mut retireds = self.retireds.pop_all();
         for r in retireds.drain(..) {
-            unsafe { (r.deleter)(r.ptr) };
+            unsafe { r.free() };
+        }
+        self.threads.free_records(self.allocator);
+        self.threads.free_segments(self.allocator);
+    }
+}
+
+/// A reclamation pass over the retired pointers of a domain, shared by `Thread::do_reclamation`
+/// and `Domain::reclaim_all`, which differ in how they scan the hazard stores and free.
+pub(crate) struct Reclamation<'d, S: HazardStore> {
+    domain: &'d Domain<S>,
+    retireds: Vec<Retired>,
+    len: usize,
+    freeable: usize,
+    #[cfg(feature = "chrome-trace")]
+    begin: Option<Instant>,
+    #[cfg(feature = "metrics")]
+    start: Instant,
+}
+
+impl<'d, S: HazardStore> Reclamation<'d, S> {
+    /// Take the retired pointers of `domain` and issue the heavy barrier, or `None` if nothing is
+    /// retired. The caller scans the protections after this.
+    pub(crate) fn begin(domain: &'d Domain<S>, config: &DomainConfig) -> Option<Self> {
+        let mut retireds = domain.retireds.pop_all();
+        if retireds.is_empty() {
+            return None;
+        }
+        #[cfg(feature = "chrome-trace")]
+        let begin = domain.tracer.begin();
+        #[cfg(feature = "metrics")]
+        let start = Instant::now();
+        if config.fifo_reclamation {
+            retireds.sort_unstable_by_key(|r| r.seq);
+        }
+
+        domain.reclamation_barriers.fetch_add(1, Ordering::Relaxed);
+        // The heavy barrier can't be replaced by a cheaper fence even for plain HP usage:
+        // * HP readers validate with `membarrier::light()`, which is only a compiler fence under
+        //   `BarrierStrategy::Membarrier`. The store-load ordering between their hazard
+        //   publication and the re-load of the source is provided by this barrier, and a fence on
+        //   our side alone wouldn't pair with it.
+        // * Whether HP++ is in use is a property of the whole domain, not of the reclaimer: other
+        //   threads may be in `try_protect_pp` or have pending unlinks, and their invalidation
+        //   relies on the epoch advanced by this barrier.
+        domain.heavy_barrier();
+        Some(Self {
+            domain,
+            len: retireds.len(),
+            retireds,
+            freeable: 0,
+            #[cfg(feature = "chrome-trace")]
+            begin,
+            #[cfg(feature = "metrics")]
+            start,
+        })
+    }
+
+    /// Take the entries that can be freed: not in `guarded` once masked by `tag_mask`, released by
+    /// the free filter, and past their grace period. The others are kept for `finish`.
+    pub(crate) fn sift(
+        &mut self,
+        guarded: &FxHashSet<*mut u8>,
+        tag_mask: usize,
+        config: &DomainConfig,
+    ) -> Vec<Retired> {
+        let domain = self.domain;
+        domain
+            .last_guarded_count
+            .store(guarded.len(), Ordering::Relaxed);
+        let dry_run = domain.is_dry_run();
+        let epoch = domain.barrier.current();
+        let mut freeable = 0;
+        let mut to_free = Vec::new();
+        let retireds = mem::take(&mut self.retireds);
+        self.retireds = retireds
+            .into_iter()
+            .filter_map(|element| {
+                if guarded.contains(&((element.ptr as usize & tag_mask) as *mut u8))
+                    || !domain.may_free(&element)
+                    || ((config.two_epoch_grace || element.grace)
+                        && !EpochBarrier::check(element.epoch, epoch))
+                {
+                    Some(element)
+                } else if dry_run {
+                    freeable += 1;
+                    Some(element)
+                } else {
+                    to_free.push(element);
+                    None
+                }
+            })
+            .collect();
+        #[cfg(feature = "debug-checks")]
+        for retired in &mut self.retireds {
+            retired.survived += 1;
+        }
+        self.freeable = freeable;
+        to_free
+    }
+
+    /// Return the kept entries to the domain and account for the others, freed by the caller.
+    /// Returns the number of those.
+    pub(crate) fn finish(self, guarded_len: usize, config: &DomainConfig) -> usize {
+        let domain = self.domain;
+        let retained = self.retireds.len();
+        let freed = self.len - retained;
+        domain.num_garbages.fetch_sub(freed, Ordering::AcqRel);
+        domain.freed_total.fetch_add(freed, Ordering::Relaxed);
+        #[cfg(feature = "metrics")]
+        {
+            let metrics = domain.metrics();
+            metrics.garbage.set(domain.num_garbages() as f64);
+            metrics.reclaimed_total.increment(freed as u64);
+            metrics
+                .pass_duration
+                .record(self.start.elapsed().as_secs_f64());
+        }
+        #[cfg(feature = "chrome-trace")]
+        domain.tracer.complete(
+            "reclaim",
+            self.begin,
+            &[
+                ("retired", self.len),
+                ("freed", freed),
+                ("guarded", guarded_len),
+            ],
+        );
+        #[cfg(not(feature = "chrome-trace"))]
+        let _ = guarded_len;
+        if self.freeable > 0 {
+            domain
+                .dry_run_freeable
+                .fetch_add(self.freeable, Ordering::Relaxed);
+        }
+        domain.retireds.push(self.retireds);
+        if let Some(on_reclaim) = config.on_reclaim {
+            on_reclaim(freed, retained);
         }
+        freed
+    }
+}
+
+/// An epoch of a domain, observed by `Domain::current_epoch`. Each heavy barrier of the domain
+/// advances it. It can't be made up from an integer, so reasoning about what happened after an
+/// epoch starts from an observation of it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Epoch(usize);
+
+impl Epoch {
+    /// The number of times the epoch advanced from `earlier` to `self`.
+    pub fn advances_since(self, earlier: Epoch) -> usize {
+        self.0.wrapping_sub(earlier.0)
     }
 }
 
 #[derive(Debug)]
-pub(crate) struct EpochBarrier(AtomicUsize);
+pub(crate) struct EpochBarrier {
+    epoch: AtomicUsize,
+    pub(crate) strategy: BarrierStrategy,
+}
 
 impl EpochBarrier {
+    const fn new(strategy: BarrierStrategy) -> Self {
+        Self {
+            epoch: AtomicUsize::new(0),
+            strategy,
+        }
+    }
+
     pub(crate) fn barrier(&self) {
-        let epoch = self.0.load(Ordering::Acquire);
-        membarrier::heavy();
+        let epoch = self.epoch.load(Ordering::Acquire);
+        match self.strategy {
+            BarrierStrategy::Membarrier => sync::heavy(),
+            BarrierStrategy::SeqCst => sync::fence(Ordering::SeqCst),
+        }
         let new_epoch = epoch.wrapping_add(1);
         let _ = self
-            .0
+            .epoch
             .compare_exchange(epoch, new_epoch, Ordering::Release, Ordering::Acquire);
     }
 
-    pub(crate) fn read(&self) -> usize {
-        let mut epoch = self.0.load(Ordering::Acquire);
+    /// The readers' side of `barrier`.
+    #[inline]
+    pub(crate) fn light(&self) {
+        match self.strategy {
+            BarrierStrategy::Membarrier => sync::light(),
+            BarrierStrategy::SeqCst => sync::fence(Ordering::SeqCst),
+        }
+    }
+
+    /// The current epoch, without ordering it with anything.
+    pub(crate) fn current(&self) -> usize {
+        self.epoch.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn read(&self, spins: usize) -> usize {
+        self.read_cached(self.epoch.load(Ordering::Acquire), spins)
+    }
+
+    /// `read` starting from an epoch the caller observed before, which skips the initial load if
+    /// the epoch hasn't changed since.
+    ///
+    /// The cache can't under-estimate the epoch: it is only compared, and the result is always a
+    /// value loaded after the light barrier, exactly like in `read`. So if a barrier advances the
+    /// epoch past the result, its heavy barrier is ordered after the light barrier here, and
+    /// thus after everything this thread did before calling this. Note that the light barrier
+    /// itself can't be skipped with the cache: without it, the load may be ordered before the
+    /// preceding invalidations, and two barriers may complete before they are visible.
+    ///
+    /// After `spins` retries, it yields the thread before each retry. It doesn't give up and
+    /// return an unstable epoch instead: if the result is behind the epoch, the hazard pointers
+    /// tagged with it are released before two barriers are ordered after the invalidation.
+    pub(crate) fn read_cached(&self, cached: usize, spins: usize) -> usize {
+        let mut epoch = cached;
+        let mut retries = 0usize;
         loop {
-            membarrier::light();
-            let new_epoch = self.0.load(Ordering::Acquire);
+            self.light();
+            let new_epoch = self.epoch.load(Ordering::Acquire);
             if epoch == new_epoch {
                 return epoch;
             }
-            epoch = new_epoch
+            epoch = new_epoch;
+            if retries >= spins {
+                std::thread::yield_now();
+            } else {
+                retries += 1;
+            }
         }
     }
 
@@ -0,0 +1,30 @@
+//! Reclamation metrics reported to the `metrics` facade, for the exporter installed by the
+//! application.
+//!
+//! The handles are registered once per domain, with the global recorder at the time. So a
+//! recorder installed after a domain's first barrier or reclamation doesn't see the domain.
+
+use metrics::{counter, gauge, histogram, Counter, Gauge, Histogram};
+
+/// The metric handles of a domain, registered on first use and kept for its lifetime.
+pub(crate) struct MetricHandles {
+    /// `hp_plus.garbage`: the number of retired pointers flushed to the domain.
+    pub(crate) garbage: Gauge,
+    /// `hp_plus.reclaimed_total`: the number of pointers freed by reclamations.
+    pub(crate) reclaimed_total: Counter,
+    /// `hp_plus.membarriers_total`: the number of heavy barriers.
+    pub(crate) membarriers_total: Counter,
+    /// `hp_plus.pass_duration`: the duration of each reclamation pass, in seconds.
+    pub(crate) pass_duration: Histogram,
+}
+
+impl MetricHandles {
+    pub(crate) fn register() -> Self {
+        Self {
+            garbage: gauge!("hp_plus.garbage"),
+            reclaimed_total: counter!("hp_plus.reclaimed_total"),
+            membarriers_total: counter!("hp_plus.membarriers_total"),
+            pass_duration: histogram!("hp_plus.pass_duration"),
+        }
+    }
+}
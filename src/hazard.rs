@@ -1,17 +1,48 @@
+use alloc::alloc::{alloc, dealloc, handle_alloc_error};
+use core::alloc::Layout;
 use core::marker::PhantomData;
-use core::sync::atomic::{AtomicBool, AtomicPtr, Ordering};
-use core::{mem, ptr};
+use core::ops::Deref;
+use core::{mem, ptr, slice};
 
+use crossbeam_utils::Backoff;
+use rustc_hash::FxHashSet;
+
+use crate::domain::EpochBarrier;
+use crate::retire::UnlinkedPtrs;
+use crate::sync::{self, AtomicBool, AtomicPtr, AtomicU32, AtomicU64, AtomicUsize, Ordering};
 use crate::thread::Thread;
 use crate::untagged;
 use crate::DEFAULT_THREAD;
 
 #[derive(Debug)]
-pub struct HazardPointer<'domain> {
-    thread: *const Thread<'domain>,
+pub struct HazardPointer<'domain, S: HazardStore = HazardArray> {
+    thread: *const Thread<'domain, S>,
     idx: usize,
 }
 
+/// The protection of a pointer by a hazard pointer, from `HazardPointer::protect_token`.
+#[derive(Debug)]
+pub struct ProtectToken<T> {
+    ptr: *mut T,
+    thread: *const (),
+    idx: usize,
+}
+
+impl<T> ProtectToken<T> {
+    /// The protected pointer.
+    pub fn as_ptr(&self) -> *mut T {
+        self.ptr
+    }
+}
+
+impl<T> Clone for ProtectToken<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T> Copy for ProtectToken<T> {}
+
 pub enum ProtectError<T> {
     Stopped,
     Changed(*mut T),
@@ -23,43 +54,114 @@ impl Default for HazardPointer<'static> {
     }
 }
 
-impl<'domain> HazardPointer<'domain> {
+// Not generic over the store, so that `HazardPointer::validate` needs no annotation.
+impl HazardPointer<'_> {
+    /// Check if `src` still points to `pointer`. If not, returns the current value.
+    ///
+    /// For a pointer `p`, if "`src` still pointing to `pointer`" implies that `p` is not retired,
+    /// then `Ok(())` means that shields set to `p` are validated.
+    ///
+    /// Having no domain to ask for its strategy, it always issues the readers' barrier of
+    /// `BarrierStrategy::Membarrier`. So it doesn't validate the shields of a
+    /// `BarrierStrategy::SeqCst` domain, whose readers validate with `try_protect` or
+    /// `validate_token`.
+    pub fn validate<T>(pointer: *mut T, src: &AtomicPtr<T>) -> Result<(), *mut T> {
+        sync::light();
+        Self::check(pointer, src)
+    }
+
+    #[inline]
+    fn check<T>(pointer: *mut T, src: &AtomicPtr<T>) -> Result<(), *mut T> {
+        let new = src.load(Ordering::Acquire);
+        if pointer == new {
+            Ok(())
+        } else {
+            Err(new)
+        }
+    }
+}
+
+impl<'domain, S: HazardStore> HazardPointer<'domain, S> {
     /// Create a hazard pointer in the given thread
-    pub fn new(thread: &mut Thread<'domain>) -> Self {
+    ///
+    /// Released slots are reused in LIFO order, so dropping a hazard pointer and creating another
+    /// one reuses the same slot without allocation.
+    pub fn new(thread: &mut Thread<'domain, S>) -> Self {
         let idx = thread.acquire();
         Self { thread, idx }
     }
 
+    /// Create a hazard pointer in the given thread, or `None` if it requires growing the hazard
+    /// array beyond the `max_hazard_bytes` of the domain.
+    pub fn try_new(thread: &mut Thread<'domain, S>) -> Option<Self> {
+        let idx = thread.try_acquire(true)?;
+        Some(Self { thread, idx })
+    }
+
+    /// The readers' barrier of the domain, ordering the protections before the validating loads.
+    #[inline]
+    fn light(&self) {
+        unsafe { (*self.thread).domain.barrier.light() }
+    }
+
+    #[inline]
+    fn store(&self) -> &S {
+        unsafe { &*(*self.thread).hazards.hazptrs.load(Ordering::Relaxed) }
+    }
+
     #[inline]
     fn slot(&self) -> &AtomicPtr<u8> {
-        unsafe {
-            let array = &*(*self.thread).hazards.hazptrs.load(Ordering::Relaxed);
-            array.get_unchecked(self.idx)
-        }
+        unsafe { self.store().slot(self.idx) }
     }
 
     /// Protect the given address.
+    ///
+    /// Protecting null protects nothing: it is equivalent to `reset_protection()`, never keeps a
+    /// retired pointer from being freed, and validates against a null source.
+    #[track_caller]
     pub fn protect_raw<T>(&mut self, ptr: *mut T) {
-        self.slot().store(ptr as *mut u8, Ordering::Release);
+        unsafe {
+            self.store()
+                .protect(self.idx, ptr as *mut u8, Ordering::Release)
+        };
     }
 
-    /// Release the protection awarded by this hazard pointer, if any.
-    pub fn reset_protection(&mut self) {
-        self.slot().store(ptr::null_mut(), Ordering::Release);
+    /// Protect the given address like `protect_raw`, returning a token to validate the protection
+    /// later with `validate_token`, e.g. after other work in between.
+    pub fn protect_token<T>(&mut self, ptr: *mut T) -> ProtectToken<T> {
+        self.protect_raw(ptr);
+        ProtectToken {
+            ptr,
+            thread: self.thread.cast(),
+            idx: self.idx,
+        }
     }
 
-    /// Check if `src` still points to `pointer`. If not, returns the current value.
-    ///
-    /// For a pointer `p`, if "`src` still pointing to `pointer`" implies that `p` is not retired,
-    /// then `Ok(())` means that shields set to `p` are validated.
-    pub fn validate<T>(pointer: *mut T, src: &AtomicPtr<T>) -> Result<(), *mut T> {
-        membarrier::light();
-        let new = src.load(Ordering::Acquire);
-        if pointer == new {
-            Ok(())
-        } else {
-            Err(new)
-        }
+    /// Check that `token` is from this hazard pointer, which still protects its pointer, and that
+    /// `src` still points to it. If `true`, the pointer is validated like with `try_protect`.
+    pub fn validate_token<T>(&self, token: ProtectToken<T>, src: &AtomicPtr<T>) -> bool {
+        token.thread == self.thread.cast()
+            && token.idx == self.idx
+            && self.slot().load(Ordering::Relaxed) == token.ptr as *mut u8
+            && {
+                self.light();
+                HazardPointer::check(token.ptr, src).is_ok()
+            }
+    }
+
+    /// Protect the given address with a relaxed store. The protection must be published with a
+    /// release fence before its validation.
+    #[inline]
+    pub(crate) fn protect_raw_relaxed<T>(&mut self, ptr: *mut T) {
+        unsafe {
+            self.store()
+                .protect(self.idx, ptr as *mut u8, Ordering::Relaxed)
+        };
+    }
+
+    /// Release the protection awarded by this hazard pointer, if any.
+    pub fn reset_protection(&mut self) {
+        unsafe { self.store().clear(self.idx) };
     }
 
     /// Try protecting `pointer` obtained from `src`. If not, returns the current value.
@@ -68,33 +170,124 @@ impl<'domain> HazardPointer<'domain> {
     /// means that this shield is validated.
     pub fn try_protect<T>(&mut self, pointer: *mut T, src: &AtomicPtr<T>) -> Result<(), *mut T> {
         self.protect_raw(pointer);
-        Self::validate(pointer, src)
+        self.light();
+        HazardPointer::check(pointer, src)
     }
 
-    /// Get a protected pointer from `src`.
+    /// Protect `expected` that the caller is about to CAS `src` against, and check that `src`
+    /// still points to it. If `true`, `expected` is protected and validated like with
+    /// `try_protect()`, so it can be dereferenced and the CAS can proceed. If `false`, `src` has
+    /// changed and the caller should reload it.
+    pub fn protect_for_cas<T>(&mut self, src: &AtomicPtr<T>, expected: *mut T) -> bool {
+        self.try_protect(expected, src).is_ok()
+    }
+
+    /// Get a protected pointer from `src`: load it, protect it, and reload `src` to validate it,
+    /// until it is stable.
     ///
-    /// See `try_protect()`.
+    /// The validating reload is `Acquire`, so the returned pointer is synchronized with the store
+    /// that published it, and the pointee can be read. See `try_protect()`.
     pub fn protect<T>(&mut self, src: &AtomicPtr<T>) -> *mut T {
+        self.protect_with_backoff(src, &Backoff::new())
+    }
+
+    /// Get a protected pointer from `src`, pausing with `backoff` after each failed validation.
+    ///
+    /// See `try_protect()`.
+    pub fn protect_with_backoff<T>(&mut self, src: &AtomicPtr<T>, backoff: &Backoff) -> *mut T {
         let mut pointer = src.load(Ordering::Relaxed);
         while let Err(new) = self.try_protect(pointer, src) {
             pointer = new;
+            backoff.spin();
         }
         pointer
     }
 
+    /// Get a protected pointer computed by `compute`, e.g. by loading through several levels of
+    /// indirection, without a source to validate it against. The result is protected and
+    /// validated by calling `compute` again, until two consecutive results agree.
+    ///
+    /// If "`compute` still returning `pointer`" implies that `pointer` is not retired, the
+    /// result is validated like with `try_protect`. `compute` may be called several times, and
+    /// the objects it dereferences must stay valid on their own, e.g. by other hazard pointers.
+    pub fn protect_computed<T>(&mut self, compute: impl Fn() -> *mut T) -> *mut T {
+        let mut pointer = compute();
+        loop {
+            self.protect_raw(pointer);
+            self.light();
+            let new = compute();
+            if pointer == new {
+                return pointer;
+            }
+            pointer = new;
+        }
+    }
+
+    /// Get a protected pointer from `src`, and arrange for `on_retire` to be called by the thread
+    /// that retires it, e.g. to refresh a cache of the object proactively. If it has been retired
+    /// already by the time this returns, `on_retire` may have been called already.
+    ///
+    /// `on_retire` stays registered until the pointer is retired, even after the protection is
+    /// released. If the pointer is null, it is never called.
+    ///
+    /// With the `retire-notify` feature, every retirement and invalidation locks the registry of
+    /// the domain to look up the callbacks.
+    #[cfg(feature = "retire-notify")]
+    pub fn protect_with_notify<T>(
+        &mut self,
+        src: &AtomicPtr<T>,
+        on_retire: impl FnOnce() + Send + 'static,
+    ) -> *mut T {
+        let domain = unsafe { (*self.thread).domain };
+        let mut on_retire: Option<Box<dyn FnOnce() + Send>> = Some(Box::new(on_retire));
+        loop {
+            let ptr = self.protect(src);
+            if ptr.is_null() {
+                return ptr;
+            }
+            let id = domain.register_notify(untagged(ptr) as *mut u8, on_retire.take().unwrap());
+            // Retirement looks up the callbacks after unlinking the pointer, so if it still is in
+            // `src` now, the callback is registered in time.
+            if src.load(Ordering::Acquire) == ptr {
+                return ptr;
+            }
+            match domain.unregister_notify(untagged(ptr) as *mut u8, id) {
+                Some(callback) => on_retire = Some(callback),
+                // Retired in the meantime, and notified.
+                None => return ptr,
+            }
+        }
+    }
+
+    /// Get a protected reference to the object `src` points to, or `None` if `src` is null.
+    ///
+    /// # Safety
+    /// * `src` only points to objects allocated with `Box`, which are reachable only via `src`.
+    /// * Objects are retired to the domain of this hazard pointer after being unlinked from `src`.
+    pub unsafe fn protect_boxed<'hp, T>(
+        &'hp mut self,
+        src: &'hp AtomicPtr<T>,
+    ) -> Option<ProtectedRef<'hp, 'domain, T, S>> {
+        let ptr = self.protect(src);
+        if ptr.is_null() {
+            return None;
+        }
+        Some(ProtectedRef { hp: self, ptr, src })
+    }
+
     /// hp++ protection
-    pub fn try_protect_pp<T, S, F>(
+    pub fn try_protect_pp<T, N, F>(
         &mut self,
         ptr: *mut T,
-        src: &S,
+        src: &N,
         src_link: &AtomicPtr<T>,
         check_stop: &F,
     ) -> Result<(), ProtectError<T>>
     where
-        F: Fn(&S) -> bool,
+        F: Fn(&N) -> bool,
     {
         self.protect_raw(ptr);
-        membarrier::light();
+        self.light();
         if check_stop(src) {
             return Err(ProtectError::Stopped);
         }
@@ -106,14 +299,14 @@ impl<'domain> HazardPointer<'domain> {
     }
 
     /// hp++ protection
-    pub fn protect_pp<T, S, F>(
+    pub fn protect_pp<T, N, F>(
         &mut self,
-        src: &S,
+        src: &N,
         src_link: &AtomicPtr<T>,
         check_stop: &F,
     ) -> Result<*mut T, ()>
     where
-        F: Fn(&S) -> bool,
+        F: Fn(&N) -> bool,
     {
         let mut ptr = src_link.load(Ordering::Relaxed);
         loop {
@@ -126,7 +319,7 @@ impl<'domain> HazardPointer<'domain> {
     }
 
     #[inline]
-    pub fn swap(x: &mut HazardPointer, y: &mut HazardPointer) {
+    pub fn swap(x: &mut HazardPointer<'_, S>, y: &mut HazardPointer<'_, S>) {
         mem::swap(&mut x.idx, &mut y.idx);
     }
 
@@ -134,7 +327,7 @@ impl<'domain> HazardPointer<'domain> {
     /// This is only possible when `to` is scanned after `self`.
     /// Correctness of this is quite subtle, so avoid using it.
     /// There are usually better alternative approaches.
-    pub fn copy_to(&mut self, to: &mut HazardPointer) -> Result<(), ()> {
+    pub fn copy_to(&mut self, to: &mut HazardPointer<'_, S>) -> Result<(), ()> {
         if to.idx <= self.idx {
             return Err(());
         }
@@ -143,69 +336,401 @@ impl<'domain> HazardPointer<'domain> {
     }
 }
 
-impl Drop for HazardPointer<'_> {
+impl<S: HazardStore> Drop for HazardPointer<'_, S> {
     fn drop(&mut self) {
         self.reset_protection();
-        unsafe { (*(self.thread as *mut Thread)).release(self.idx) };
+        unsafe { (*(self.thread as *mut Thread<'_, S>)).release(self.idx) };
+    }
+}
+
+/// A reference to an object protected by a hazard pointer, obtained by
+/// `HazardPointer::protect_boxed`.
+#[derive(Debug)]
+pub struct ProtectedRef<'hp, 'domain, T, S: HazardStore = HazardArray> {
+    hp: &'hp mut HazardPointer<'domain, S>,
+    ptr: *mut T,
+    src: &'hp AtomicPtr<T>,
+}
+
+impl<'hp, 'domain, T, S: HazardStore> ProtectedRef<'hp, 'domain, T, S> {
+    pub fn as_ptr(&self) -> *mut T {
+        self.ptr
+    }
+
+    /// Try taking the ownership of the object by unlinking it from its source (setting it to null).
+    ///
+    /// Returns `None` if the source has changed, in which case nothing happens. Also returns `None`
+    /// if the object is unlinked but other threads are still protecting it: then it is retired to
+    /// `thread`.
+    ///
+    /// # Panics
+    /// If `thread` doesn't belong to the domain of the hazard pointer, whose threads the
+    /// protections are collected from.
+    pub fn try_into_owned(self, thread: &mut Thread<'domain, S>) -> Option<Box<T>> {
+        assert!(
+            ptr::eq(thread.domain, unsafe { (*self.hp.thread).domain }),
+            "`thread` must belong to the domain of the hazard pointer"
+        );
+        if self
+            .src
+            .compare_exchange(
+                self.ptr,
+                ptr::null_mut(),
+                Ordering::AcqRel,
+                Ordering::Relaxed,
+            )
+            .is_err()
+        {
+            return None;
+        }
+        self.hp.reset_protection();
+
+        // Same as reclamation: after the barrier, a reader either has its protection visible to us
+        // or fails to validate it against the unlinked source.
+        thread.domain.heavy_barrier();
+        let mut guarded_ptrs = FxHashSet::default();
+        let tag_mask = thread.domain.config().tag_mask();
+        thread.domain.collect_guarded_ptrs_masked(
+            &thread.hazards.scan_hazard,
+            &mut guarded_ptrs,
+            tag_mask,
+        );
+        if guarded_ptrs.contains(&((self.ptr as usize & tag_mask) as *mut u8)) {
+            unsafe { thread.retire(self.ptr) };
+            None
+        } else {
+            Some(unsafe { Box::from_raw(self.ptr) })
+        }
+    }
+}
+
+impl<T, S: HazardStore> Deref for ProtectedRef<'_, '_, T, S> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.ptr }
     }
 }
 
 /// Push-only list of recyclable thread records
 #[derive(Debug)]
-pub(crate) struct ThreadRecords {
-    head: AtomicPtr<ThreadRecord>,
+pub(crate) struct ThreadRecords<S: HazardStore = HazardArray> {
+    head: AtomicPtr<ThreadRecord<S>>,
+    /// Total size of the current hazard arrays of all records, in bytes.
+    pub(crate) hazard_bytes: AtomicUsize,
+    /// Stack of the available records, as a version in the upper half and the index of the top
+    /// record plus one in the lower half (zero if empty). The version is bumped by each update, so
+    /// that a pop doesn't succeed on a top that was popped and pushed back in the meantime (ABA).
+    /// Records are never freed, so reading the next index of a stale top is fine.
+    free: AtomicU64,
+    /// Number of records, i.e. the index of the next new record.
+    len: AtomicUsize,
+    /// Records by their index. Segment `k` holds the `2^k` records from index `2^k - 1`, and is
+    /// allocated by the first of them.
+    segments: [AtomicPtr<AtomicPtr<ThreadRecord<S>>>; RECORD_SEGMENTS],
 }
 
+const RECORD_SEGMENTS: usize = u32::BITS as usize;
+
 /// Single-writer growable hazard pointer array.
 /// Does not shrink. (Use single-writer doubly linked list? see HP04)
 #[derive(Debug)]
-pub struct ThreadRecord {
-    pub(crate) next: *mut ThreadRecord,
+pub struct ThreadRecord<S: HazardStore = HazardArray> {
+    pub(crate) next: *mut ThreadRecord<S>,
     pub(crate) available: AtomicBool,
-    pub(crate) hazptrs: AtomicPtr<HazardArray>,
+    pub(crate) hazptrs: AtomicPtr<S>,
+    /// Number of hazard pointers currently acquired by the owner. Only the owner modifies it, and
+    /// the next owner acquires its last value via the stack of available records.
+    pub(crate) active_hazards: AtomicUsize,
+    /// Index in `ThreadRecords`.
+    index: u32,
+    /// The index of the next available record plus one (zero if none), while available.
+    next_free: AtomicU32,
+    /// Unlinked pointers left by an owner that panicked before invalidating them. While it is
+    /// non-null, the record stays unavailable to keep their frontier protected.
+    pub(crate) abandoned: AtomicPtr<Vec<UnlinkedPtrs>>,
+    /// The hazard slot of the owner's reclamations, protecting the store they scan. Every
+    /// reclamation counts it as protected.
+    pub(crate) scan_hazard: AtomicPtr<u8>,
 }
 
-type HazardArray = Vec<AtomicPtr<u8>>;
+/// Allocator of the thread records and hazard arrays of a domain.
+///
+/// This is the hook for placing them in memory shared between processes. Note that this alone
+/// does not make reclamation work across processes: the `Domain` itself must be placed in the
+/// shared memory at the same address in every process, and the barrier must be process-crossing
+/// (`MEMBARRIER_CMD_GLOBAL`), whereas the default barrier only orders the threads of this process.
+///
+/// # Safety
+/// `allocate` must return a block that fits `layout`, or null on failure. The block must be valid
+/// until it is passed to `deallocate` with the same layout.
+pub unsafe trait RecordAllocator: Sync {
+    fn allocate(&self, layout: Layout) -> *mut u8;
+
+    /// # Safety
+    /// `ptr` must be allocated by `self.allocate(layout)`.
+    unsafe fn deallocate(&self, ptr: *mut u8, layout: Layout);
+}
+
+/// `RecordAllocator` using the global allocator.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct GlobalRecordAllocator;
+
+unsafe impl RecordAllocator for GlobalRecordAllocator {
+    fn allocate(&self, layout: Layout) -> *mut u8 {
+        unsafe { alloc(layout) }
+    }
+
+    unsafe fn deallocate(&self, ptr: *mut u8, layout: Layout) {
+        dealloc(ptr, layout)
+    }
+}
+
+/// Number of slots of the hazard array of a new thread record, below which it doesn't shrink.
+pub(crate) const HAZARD_ARRAY_INIT_SIZE: usize = 64;
+
+/// Storage of the hazard pointers of a thread record, e.g. an array of slots indexed by the
+/// hazard pointers.
+///
+/// A store has a fixed number of slots. The owner of the record grows or shrinks it by `resize`,
+/// publishing the new store in place of the old one, which is retired. Reclaimers read the
+/// published store concurrently with the owner's protections.
+///
+/// # Safety
+/// * `alloc` must return a valid store of `len` empty slots, and `resize` one of `len` slots, which
+///   `dealloc` frees. They should allocate from `allocator`, the `RecordAllocator` of the domain.
+/// * `slot(idx)` must return the same slot for the lifetime of the store, distinct for each index.
+/// * `iter_protected` must yield every non-null pointer stored in the slots before the call, with
+///   at least `Acquire` loads.
+#[allow(clippy::len_without_is_empty)]
+pub unsafe trait HazardStore: core::fmt::Debug + Send + Sync + Sized + 'static {
+    /// Allocates a store of `len` empty slots.
+    fn alloc(len: usize, allocator: &'static dyn RecordAllocator) -> *mut Self;
+
+    /// Deleter of a retired store.
+    ///
+    /// # Safety
+    /// `ptr` must be a store allocated by `alloc` or `resize`, which is no longer used.
+    unsafe fn dealloc(ptr: *mut u8);
+
+    /// Size of a store of `len` slots, in bytes.
+    fn size_of(len: usize) -> usize;
+
+    /// Number of slots.
+    fn len(&self) -> usize;
+
+    /// # Safety
+    /// `idx` must be less than `self.len()`.
+    unsafe fn slot(&self, idx: usize) -> &AtomicPtr<u8>;
+
+    /// Store `ptr` in the slot of `idx`.
+    ///
+    /// # Safety
+    /// `idx` must be less than `self.len()`.
+    #[inline]
+    unsafe fn protect(&self, idx: usize, ptr: *mut u8, order: Ordering) {
+        self.slot(idx).store(ptr, order);
+    }
+
+    /// Empty the slot of `idx`.
+    ///
+    /// # Safety
+    /// `idx` must be less than `self.len()`.
+    #[inline]
+    unsafe fn clear(&self, idx: usize) {
+        self.protect(idx, ptr::null_mut(), Ordering::Release);
+    }
 
-impl ThreadRecords {
+    /// The non-null pointers in the slots.
+    fn iter_protected(&self) -> impl Iterator<Item = *mut u8> + '_ {
+        (0..self.len()).filter_map(move |idx| {
+            // The ordering of this load doesn't depend on how the barrier is implemented. Whether
+            // it's `membarrier::heavy()` paired with the readers' `light()`, or `SeqCst` fences on
+            // both sides, the barrier issued by the reclaimer before scanning is what forbids the
+            // store-buffering outcome (the reader missing the unlink while we miss its hazard).
+            // With either, a plain load observes every hazard published before validation, so a
+            // `SeqCst` load would add nothing.
+            let value = unsafe { self.slot(idx) }.load(Ordering::Acquire);
+            (!value.is_null()).then_some(value)
+        })
+    }
+
+    /// Allocates a store of `len` slots, holding the protections of the first slots of `self`.
+    fn resize(&self, len: usize, allocator: &'static dyn RecordAllocator) -> *mut Self {
+        let new = Self::alloc(len, allocator);
+        for idx in 0..len.min(self.len()) {
+            unsafe {
+                let value = self.slot(idx).load(Ordering::Relaxed);
+                (*new).slot(idx).store(value, Ordering::Relaxed);
+            }
+        }
+        new
+    }
+}
+
+/// The default `HazardStore`: an array of slots laid out right after this header.
+#[repr(C)]
+pub struct HazardArray {
+    len: usize,
+    allocator: &'static dyn RecordAllocator,
+}
+
+impl HazardArray {
+    const SLOTS_OFFSET: usize = mem::size_of::<Self>();
+
+    fn layout(len: usize) -> Layout {
+        let slots = Layout::array::<AtomicPtr<u8>>(len).unwrap();
+        let (layout, offset) = Layout::new::<Self>().extend(slots).unwrap();
+        debug_assert_eq!(offset, Self::SLOTS_OFFSET);
+        layout
+    }
+
+    fn slots(&self) -> &[AtomicPtr<u8>] {
+        unsafe {
+            let slots = (self as *const Self).cast::<u8>().add(Self::SLOTS_OFFSET);
+            slice::from_raw_parts(slots.cast(), self.len)
+        }
+    }
+}
+
+unsafe impl HazardStore for HazardArray {
+    fn alloc(len: usize, allocator: &'static dyn RecordAllocator) -> *mut Self {
+        let layout = Self::layout(len);
+        let ptr = allocator.allocate(layout);
+        if ptr.is_null() {
+            handle_alloc_error(layout);
+        }
+        unsafe {
+            ptr.cast::<Self>().write(Self { len, allocator });
+            let slots = ptr.add(Self::SLOTS_OFFSET).cast::<AtomicPtr<u8>>();
+            for i in 0..len {
+                slots.add(i).write(AtomicPtr::new(ptr::null_mut()));
+            }
+        }
+        ptr.cast()
+    }
+
+    unsafe fn dealloc(ptr: *mut u8) {
+        let array = &*ptr.cast::<Self>();
+        array.allocator.deallocate(ptr, Self::layout(array.len));
+    }
+
+    fn size_of(len: usize) -> usize {
+        Self::layout(len).size()
+    }
+
+    fn len(&self) -> usize {
+        self.len
+    }
+
+    #[inline]
+    unsafe fn slot(&self, idx: usize) -> &AtomicPtr<u8> {
+        self.slots().get_unchecked(idx)
+    }
+}
+
+// `SLOTS_OFFSET` must be suitably aligned for the slots.
+const _: () = assert!(mem::align_of::<HazardArray>() >= mem::align_of::<AtomicPtr<u8>>());
+
+impl core::fmt::Debug for HazardArray {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("HazardArray")
+            .field("len", &self.len)
+            .finish()
+    }
+}
+
+impl<S: HazardStore> ThreadRecords<S> {
     pub(crate) const fn new() -> Self {
         Self {
             head: AtomicPtr::new(ptr::null_mut()),
+            hazard_bytes: AtomicUsize::new(0),
+            free: AtomicU64::new(0),
+            len: AtomicUsize::new(0),
+            segments: [const { AtomicPtr::new(ptr::null_mut()) }; RECORD_SEGMENTS],
         }
     }
 
-    pub(crate) fn acquire(&self) -> (&ThreadRecord, Vec<usize>) {
+    pub(crate) fn acquire(
+        &self,
+        allocator: &'static dyn RecordAllocator,
+    ) -> (&ThreadRecord<S>, Vec<usize>) {
         if let Some(avail) = self.try_acquire_available() {
             return avail;
         }
-        self.acquire_new()
+        self.acquire_new(allocator)
     }
 
-    fn try_acquire_available(&self) -> Option<(&ThreadRecord, Vec<usize>)> {
-        let mut cur = self.head.load(Ordering::Acquire);
-        while let Some(cur_ref) = unsafe { cur.as_ref() } {
-            if cur_ref.available.load(Ordering::Relaxed)
-                && cur_ref
-                    .available
-                    .compare_exchange(true, false, Ordering::Relaxed, Ordering::Relaxed)
-                    .is_ok()
-            {
-                let len = unsafe { &*cur_ref.hazptrs.load(Ordering::Relaxed) }.len();
-                return Some((cur_ref, (0..len).collect()));
+    /// Segment and offset of the record of `index`.
+    fn locate(index: u32) -> (usize, usize) {
+        let segment = (index + 1).ilog2() as usize;
+        (segment, (index + 1 - (1 << segment)) as usize)
+    }
+
+    /// # Safety
+    /// The record of `index` must have been published.
+    unsafe fn get(&self, index: u32) -> &ThreadRecord<S> {
+        let (segment, offset) = Self::locate(index);
+        let segment = self.segments[segment].load(Ordering::Acquire);
+        &*(*segment.add(offset)).load(Ordering::Acquire)
+    }
+
+    fn try_acquire_available(&self) -> Option<(&ThreadRecord<S>, Vec<usize>)> {
+        let mut free = self.free.load(Ordering::Acquire);
+        loop {
+            let top = free as u32;
+            if top == 0 {
+                return None;
+            }
+            let record = unsafe { self.get(top - 1) };
+            let next = record.next_free.load(Ordering::Relaxed);
+            let version = (free >> 32).wrapping_add(1);
+            match self.free.compare_exchange_weak(
+                free,
+                version << 32 | next as u64,
+                Ordering::Acquire,
+                Ordering::Acquire,
+            ) {
+                Ok(_) => {
+                    record.available.store(false, Ordering::Relaxed);
+                    let len = unsafe { &*record.hazptrs.load(Ordering::Relaxed) }.len();
+                    return Some((record, (0..len).collect()));
+                }
+                Err(current) => free = current,
             }
-            cur = cur_ref.next;
         }
-        None
     }
 
-    fn acquire_new(&self) -> (&ThreadRecord, Vec<usize>) {
-        const HAZARD_ARRAY_INIT_SIZE: usize = 64;
-        let array = Vec::from(unsafe { mem::zeroed::<[AtomicPtr<u8>; HAZARD_ARRAY_INIT_SIZE]>() });
-        let new = Box::leak(Box::new(ThreadRecord {
-            hazptrs: AtomicPtr::new(Box::into_raw(Box::new(array))),
-            next: ptr::null_mut(),
-            available: AtomicBool::new(false),
-        }));
+    fn acquire_new(
+        &self,
+        allocator: &'static dyn RecordAllocator,
+    ) -> (&ThreadRecord<S>, Vec<usize>) {
+        let index = self.len.fetch_add(1, Ordering::Relaxed);
+        let index = u32::try_from(index)
+            .ok()
+            .filter(|&index| index < u32::MAX)
+            .expect("too many thread records");
+        let layout = Layout::new::<ThreadRecord<S>>();
+        let new = allocator.allocate(layout).cast::<ThreadRecord<S>>();
+        if new.is_null() {
+            handle_alloc_error(layout);
+        }
+        self.hazard_bytes
+            .fetch_add(S::size_of(HAZARD_ARRAY_INIT_SIZE), Ordering::Relaxed);
+        let new = unsafe {
+            new.write(ThreadRecord {
+                hazptrs: AtomicPtr::new(S::alloc(HAZARD_ARRAY_INIT_SIZE, allocator)),
+                next: ptr::null_mut(),
+                available: AtomicBool::new(false),
+                active_hazards: AtomicUsize::new(0),
+                index,
+                next_free: AtomicU32::new(0),
+                abandoned: AtomicPtr::new(ptr::null_mut()),
+                scan_hazard: AtomicPtr::new(ptr::null_mut()),
+            });
+            &mut *new
+        };
+        self.publish(new, allocator);
 
         let mut head = self.head.load(Ordering::Relaxed);
         loop {
@@ -220,11 +745,94 @@ impl ThreadRecords {
         }
     }
 
-    pub(crate) fn release(&self, rec: &ThreadRecord) {
+    /// Store `record` at its index, allocating its segment if needed.
+    fn publish(&self, record: &ThreadRecord<S>, allocator: &'static dyn RecordAllocator) {
+        let (segment, offset) = Self::locate(record.index);
+        let slot = &self.segments[segment];
+        let mut entries = slot.load(Ordering::Acquire);
+        if entries.is_null() {
+            let layout = Self::segment_layout(segment);
+            let new = allocator
+                .allocate(layout)
+                .cast::<AtomicPtr<ThreadRecord<S>>>();
+            if new.is_null() {
+                handle_alloc_error(layout);
+            }
+            for i in 0..1 << segment {
+                unsafe { new.add(i).write(AtomicPtr::new(ptr::null_mut())) };
+            }
+            entries = match slot.compare_exchange(
+                ptr::null_mut(),
+                new,
+                Ordering::AcqRel,
+                Ordering::Acquire,
+            ) {
+                Ok(_) => new,
+                Err(current) => {
+                    unsafe { allocator.deallocate(new.cast(), layout) };
+                    current
+                }
+            };
+        }
+        unsafe { &*entries.add(offset) }.store(record as *const _ as *mut _, Ordering::Release);
+    }
+
+    fn segment_layout(segment: usize) -> Layout {
+        Layout::array::<AtomicPtr<ThreadRecord<S>>>(1 << segment).unwrap()
+    }
+
+    /// Free the records and their current stores. The stores they replaced were retired.
+    pub(crate) fn free_records(&mut self, allocator: &'static dyn RecordAllocator) {
+        let mut cur = mem::replace(self.head.get_mut(), ptr::null_mut());
+        while !cur.is_null() {
+            let record = unsafe { &mut *cur };
+            let store = *record.hazptrs.get_mut();
+            if !store.is_null() {
+                unsafe { S::dealloc(store.cast()) };
+            }
+            let next = record.next;
+            unsafe { allocator.deallocate(cur.cast(), Layout::new::<ThreadRecord<S>>()) };
+            cur = next;
+        }
+    }
+
+    /// Free the index segments, after `free_records`.
+    pub(crate) fn free_segments(&mut self, allocator: &'static dyn RecordAllocator) {
+        for (segment, slot) in self.segments.iter_mut().enumerate() {
+            let entries = mem::replace(slot.get_mut(), ptr::null_mut());
+            if !entries.is_null() {
+                unsafe { allocator.deallocate(entries.cast(), Self::segment_layout(segment)) };
+            }
+        }
+    }
+
+    /// Allocate `n` available records ahead of time.
+    pub(crate) fn reserve(&self, n: usize, allocator: &'static dyn RecordAllocator) {
+        for _ in 0..n {
+            let (record, _) = self.acquire_new(allocator);
+            self.release(record);
+        }
+    }
+
+    pub(crate) fn release(&self, rec: &ThreadRecord<S>) {
         rec.available.store(true, Ordering::Release);
+        let mut free = self.free.load(Ordering::Relaxed);
+        loop {
+            rec.next_free.store(free as u32, Ordering::Relaxed);
+            let version = (free >> 32).wrapping_add(1);
+            match self.free.compare_exchange_weak(
+                free,
+                version << 32 | (rec.index + 1) as u64,
+                Ordering::Release,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => return,
+                Err(current) => free = current,
+            }
+        }
     }
 
-    pub(crate) fn iter(&self) -> ThreadRecordsIter<'_> {
+    pub(crate) fn iter(&self) -> ThreadRecordsIter<'_, S> {
         ThreadRecordsIter {
             cur: self.head.load(Ordering::Acquire).cast_const(),
             _marker: PhantomData,
@@ -232,13 +840,13 @@ impl ThreadRecords {
     }
 }
 
-pub(crate) struct ThreadRecordsIter<'domain> {
-    cur: *const ThreadRecord,
-    _marker: PhantomData<&'domain ThreadRecord>,
+pub(crate) struct ThreadRecordsIter<'domain, S: HazardStore> {
+    cur: *const ThreadRecord<S>,
+    _marker: PhantomData<&'domain ThreadRecord<S>>,
 }
 
-impl<'domain> Iterator for ThreadRecordsIter<'domain> {
-    type Item = &'domain ThreadRecord;
+impl<'domain, S: HazardStore> Iterator for ThreadRecordsIter<'domain, S> {
+    type Item = &'domain ThreadRecord<S>;
 
     fn next(&mut self) -> Option<Self::Item> {
         loop {
@@ -251,40 +859,30 @@ impl<'domain> Iterator for ThreadRecordsIter<'domain> {
     }
 }
 
-impl ThreadRecord {
-    pub(crate) fn iter<'domain>(
+impl<S: HazardStore> ThreadRecord<S> {
+    /// Add the pointers protected by this record to `guarded`, masked by `tag_mask`, protecting
+    /// its store from being freed with `slot` meanwhile. `slot` is a scan slot, which every
+    /// reclamation of the domain counts as protected, so that the scan doesn't acquire a hazard
+    /// pointer, which may grow the hazard array of the reclaimer and retire the old one.
+    pub(crate) fn collect_protected(
         &self,
-        reader: &mut Thread<'domain>,
-    ) -> ThreadHazardArrayIter<'domain> {
-        let mut hp = HazardPointer::new(reader);
-        let array = hp.protect(&self.hazptrs);
-        ThreadHazardArrayIter {
-            array: unsafe { &*array }.as_slice(),
-            idx: 0,
-            _hp: hp,
-        }
-    }
-}
-
-pub(crate) struct ThreadHazardArrayIter<'domain> {
-    array: *const [AtomicPtr<u8>],
-    idx: usize,
-    _hp: HazardPointer<'domain>,
-}
-
-impl<'domain> Iterator for ThreadHazardArrayIter<'domain> {
-    type Item = *mut u8;
-
-    fn next(&mut self) -> Option<Self::Item> {
-        let array = unsafe { &*self.array };
-        for i in self.idx..array.len() {
-            self.idx += 1;
-            let slot = unsafe { array.get_unchecked(i) };
-            let value = slot.load(Ordering::Acquire);
-            if !value.is_null() {
-                return Some(value);
+        slot: &AtomicPtr<u8>,
+        barrier: &EpochBarrier,
+        guarded: &mut FxHashSet<*mut u8>,
+        tag_mask: usize,
+    ) {
+        let mut store = self.hazptrs.load(Ordering::Relaxed);
+        loop {
+            slot.store(store.cast(), Ordering::Release);
+            barrier.light();
+            let new = self.hazptrs.load(Ordering::Acquire);
+            if new == store {
+                break;
             }
+            store = new;
         }
-        None
+        let protected = unsafe { &*store }.iter_protected();
+        guarded.extend(protected.map(|ptr| (ptr as usize & tag_mask) as *mut u8));
+        slot.store(ptr::null_mut(), Ordering::Release);
     }
 }
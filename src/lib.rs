@@ -1,20 +1,56 @@
+// Collections and raw allocation come from `alloc`, but the crate requires `std`: the guarded set
+// is a `HashSet`, the membarrier barriers are system calls, and the domain uses `Mutex`, `Instant`
+// and thread-locals.
+extern crate alloc;
+
+mod collector;
 mod domain;
+#[cfg(feature = "metrics")]
+mod exporter;
 mod hazard;
+mod local;
 mod retire;
+mod sync;
 mod tag;
 mod thread;
+#[cfg(feature = "chrome-trace")]
+mod trace;
+#[cfg(feature = "debug-checks")]
+mod tracking;
+
+#[cfg(feature = "structures")]
+pub mod structures;
 
+pub use crossbeam_utils::Backoff;
+pub use hazard::GlobalRecordAllocator;
+pub use hazard::HazardArray;
 pub use hazard::HazardPointer;
+pub use hazard::HazardStore;
 pub use hazard::ProtectError;
+pub use hazard::ProtectToken;
+pub use hazard::ProtectedRef;
+pub use hazard::RecordAllocator;
 pub use membarrier::light;
 pub use tag::*;
 
 use core::cell::RefCell;
 use std::thread_local;
+use sync::AtomicPtr;
 
-use crate::domain::Domain;
-pub use crate::thread::Thread;
+pub use crate::collector::CollectorHandle;
+pub use crate::domain::{BarrierStrategy, Domain, DomainConfig, DomainStats, Epoch};
+pub use crate::local::LocalThread;
+pub use crate::retire::Retired;
+pub use crate::thread::{CollectionGuard, Thread};
+#[cfg(feature = "debug-checks")]
+pub use crate::tracking::{AllocationTracker, TrackingAllocator};
 
+/// The domain of the free functions (`retire`, `protect`, ...) and of `HazardPointer::default`,
+/// with a thread-local `Thread` per thread, which is dropped when the thread exits.
+///
+/// Pointers retired by the free functions are only kept by hazard pointers of this domain, so
+/// objects retired to it must be protected by `protect` or `HazardPointer::default`, never by hazard
+/// pointers of another domain.
 pub static DEFAULT_DOMAIN: Domain = Domain::new();
 
 // NOTE: MUST NOT take raw pointer to TLS. They randomly move???
@@ -22,12 +58,34 @@ thread_local! {
     static DEFAULT_THREAD: RefCell<Box<Thread<'static>>> = RefCell::new(Box::new(Thread::new(&DEFAULT_DOMAIN)));
 }
 
+/// `DEFAULT_THREAD` for the free functions, which may be called from thread-local destructors.
+static DEFAULT_LOCAL_THREAD: LocalThread = LocalThread::new(&DEFAULT_THREAD, &DEFAULT_DOMAIN);
+
 pub trait Unlink<T> {
     fn do_unlink(&self) -> Result<Vec<*mut T>, ()>;
 }
 
 pub trait Invalidate {
     fn invalidate(&self);
+
+    /// Whether `invalidate` has taken effect on this object.
+    ///
+    /// With the `debug-checks` feature, this is asserted right after invalidation to catch broken
+    /// `invalidate` implementations. The default implementation opts out of the check.
+    fn is_invalidated(&self) -> bool {
+        true
+    }
+}
+
+/// Get a protected pointer from `src` like `HazardPointer::protect`, with a hazard pointer of the
+/// thread-local `Thread` of `DEFAULT_DOMAIN`. The pointer stays protected until the returned hazard
+/// pointer is dropped.
+///
+/// As `HazardPointer::default`, this panics in the thread-local destructors of an exiting thread.
+pub fn protect<T>(src: &AtomicPtr<T>) -> (HazardPointer<'static>, *mut T) {
+    let mut hp = HazardPointer::default();
+    let ptr = hp.protect(src);
+    (hp, ptr)
 }
 
 /// Retire a pointer, in the thread-local retired pointer bag.
@@ -36,7 +94,7 @@ pub trait Invalidate {
 /// TODO
 #[inline]
 pub unsafe fn retire<T>(ptr: *mut T) {
-    DEFAULT_THREAD.with(|t| t.borrow_mut().retire(ptr))
+    DEFAULT_LOCAL_THREAD.retire(ptr)
 }
 
 /// Protects `links` and try unlinking by `do_unlink`. if successful, mark the returned nodes as stopped and
@@ -51,12 +109,12 @@ pub unsafe fn try_unlink<T>(unlink: impl Unlink<T>, frontier: &[*mut T]) -> bool
 where
     T: Invalidate,
 {
-    DEFAULT_THREAD.with(|t| t.borrow_mut().try_unlink(unlink, frontier))
+    DEFAULT_LOCAL_THREAD.with(|t| t.try_unlink(unlink, frontier))
 }
 
 /// Trigger reclamation
 pub fn do_reclamation() {
-    DEFAULT_THREAD.with(|t| {
-        t.borrow_mut().do_reclamation();
+    DEFAULT_LOCAL_THREAD.with(|t| {
+        t.do_reclamation();
     })
 }
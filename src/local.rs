@@ -0,0 +1,94 @@
+use core::cell::RefCell;
+use std::thread::LocalKey;
+
+use crate::domain::Domain;
+use crate::thread::Thread;
+
+/// A thread-local `Thread` of a `'static` domain, declared with `thread_local_thread!`.
+///
+/// Accessing a plain `thread_local!` `Thread` panics once its destructor has run, which happens in
+/// an unspecified order with the other thread-local destructors of the exiting thread. So
+/// retiring from the destructor of another thread-local is a footgun. `with` instead falls back to
+/// a temporary `Thread` that is dropped right after, which flushes its retired pointers to the
+/// domain. The domain is `'static`, so it always outlives the thread-local `Thread`.
+#[derive(Debug)]
+pub struct LocalThread {
+    key: &'static LocalKey<RefCell<Box<Thread<'static>>>>,
+    domain: &'static Domain,
+}
+
+impl LocalThread {
+    #[doc(hidden)]
+    pub const fn new(
+        key: &'static LocalKey<RefCell<Box<Thread<'static>>>>,
+        domain: &'static Domain,
+    ) -> Self {
+        Self { key, domain }
+    }
+
+    /// Run `f` with the `Thread` of the current thread. If it has been destroyed, or is already
+    /// in use by an enclosing `with`, `f` runs with a temporary `Thread` instead.
+    ///
+    /// The temporary `Thread` is not free: it acquires a thread record (allocating one if none is
+    /// available) and releases it after `f`, flushing its retired pointers to the domain with a
+    /// heavy barrier. To only retire a pointer, `retire` avoids that.
+    pub fn with<R>(&self, f: impl FnOnce(&mut Thread<'static>) -> R) -> R {
+        let mut f = Some(f);
+        let result = self.key.try_with(|thread| {
+            thread
+                .try_borrow_mut()
+                .ok()
+                .map(|mut thread| (f.take().unwrap())(&mut thread))
+        });
+        match result {
+            Ok(Some(result)) => result,
+            _ => (f.take().unwrap())(&mut Thread::new(self.domain)),
+        }
+    }
+
+    /// Retire `ptr` with the `Thread` of the current thread, like `Thread::retire`. If it is
+    /// unavailable (see `with`), `ptr` is retired to the domain directly, like
+    /// `Domain::retire_global`, instead of with a temporary `Thread`.
+    ///
+    /// # Safety
+    /// The same as `Thread::retire`.
+    pub unsafe fn retire<T>(&self, ptr: *mut T) {
+        let result = self.key.try_with(|thread| {
+            thread
+                .try_borrow_mut()
+                .ok()
+                .map(|mut thread| thread.retire(ptr))
+        });
+        if !matches!(result, Ok(Some(()))) {
+            self.domain.retire_direct(ptr);
+        }
+    }
+}
+
+/// Declare a `LocalThread` of a `'static` domain.
+///
+/// Its nested uses, and the uses after the `Thread` is destroyed, make a temporary `Thread` each,
+/// which acquires and releases a thread record (see `LocalThread::with`).
+///
+/// ```
+/// use hp_pp::{thread_local_thread, Domain};
+///
+/// static DOMAIN: Domain = Domain::new();
+///
+/// thread_local_thread!(static THREAD = &DOMAIN;);
+///
+/// THREAD.with(|thread| unsafe { thread.retire(Box::into_raw(Box::new(42))) });
+/// ```
+#[macro_export]
+macro_rules! thread_local_thread {
+    ($(#[$attr:meta])* $vis:vis static $name:ident = $domain:expr;) => {
+        $(#[$attr])*
+        $vis static $name: $crate::LocalThread = {
+            ::std::thread_local! {
+                static KEY: ::core::cell::RefCell<::std::boxed::Box<$crate::Thread<'static>>> =
+                    ::core::cell::RefCell::new(::std::boxed::Box::new($crate::Thread::new($domain)));
+            }
+            $crate::LocalThread::new(&KEY, $domain)
+        };
+    };
+}
@@ -1,65 +1,338 @@
+use alloc::alloc::dealloc;
+use core::alloc::Layout;
+use core::mem;
 use core::ptr;
-use core::sync::atomic::{AtomicPtr, Ordering};
-use std::mem;
 
+use rustc_hash::FxHashMap;
+
+use crate::hazard::HazardStore;
+use crate::sync::{AtomicPtr, Ordering};
 use crate::{HazardPointer, Invalidate};
 
-#[derive(Debug, Clone, Copy)]
-pub(crate) struct Retired {
+/// A retired pointer with its deleter, e.g. detached from a thread by `Thread::detach` or
+/// constructed to be adopted by `Thread::adopt`. It owns the garbage, so it can't be copied.
+#[derive(Debug)]
+pub struct Retired {
     pub(crate) ptr: *mut u8,
-    pub(crate) deleter: unsafe fn(ptr: *mut u8),
+    pub(crate) deleter: Deleter,
+    /// Retirement order in the domain, stamped on flush if `fifo_reclamation` is enabled.
+    pub(crate) seq: usize,
+    /// Epoch of the domain, stamped on flush if `two_epoch_grace` is enabled.
+    pub(crate) epoch: usize,
+    /// Freed before the other entries of a reclamation, set by `Thread::retire_hot`.
+    pub(crate) hot: bool,
+    /// Freed only after the epoch has advanced twice since `epoch`, regardless of
+    /// `two_epoch_grace`. Set by `Thread::defer`.
+    pub(crate) grace: bool,
+    /// Retired by the crate itself, i.e. an old hazard store or a `Thread::defer` call, which the
+    /// free filter of the domain doesn't apply to.
+    pub(crate) internal: bool,
+    /// Number of reclamations that kept this entry retired.
+    #[cfg(feature = "debug-checks")]
+    pub(crate) survived: usize,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Deleter {
+    Fn(unsafe fn(ptr: *mut u8)),
+    /// A `Box`: `drop` in place, then `std::alloc::dealloc` with `layout`.
+    Boxed {
+        drop: unsafe fn(ptr: *mut u8),
+        layout: Layout,
+    },
+    /// `std::alloc::dealloc` with the layout of the allocation.
+    Dealloc(Layout),
 }
 
-pub(crate) struct Unlinked<'domain> {
+impl Deleter {
+    /// The deleter of a `Box<T>`.
+    pub(crate) fn boxed<T>() -> Self {
+        Deleter::Boxed {
+            drop: drop_in_place::<T>,
+            layout: Layout::new::<T>(),
+        }
+    }
+
+    /// Identifies the entries freed the same way: by the same function, and with the same layout
+    /// if any.
+    fn key(&self) -> (usize, Option<Layout>) {
+        match self {
+            Deleter::Fn(deleter) => (*deleter as usize, None),
+            Deleter::Boxed { drop, layout } => (*drop as usize, Some(*layout)),
+            Deleter::Dealloc(layout) => (0, Some(*layout)),
+        }
+    }
+}
+
+/// Frees the memory of a `Box` of `layout`.
+unsafe fn dealloc_box(ptr: *mut u8, layout: Layout) {
+    // A zero-sized box owns no allocation.
+    if layout.size() != 0 {
+        dealloc(ptr, layout);
+    }
+}
+
+pub(crate) struct Unlinked<'domain, S: HazardStore> {
+    ptrs: UnlinkedPtrs,
+    hps: Vec<HazardPointer<'domain, S>>,
+}
+
+/// Unlinked pointers of a type, to be invalidated.
+pub(crate) struct UnlinkedPtrs {
     ptrs: Vec<*mut u8>,
     invalidater: unsafe fn(*mut u8),
-    deleter: unsafe fn(*mut u8),
-    hps: Vec<HazardPointer<'domain>>,
+    #[cfg(feature = "debug-checks")]
+    is_invalidated: unsafe fn(*mut u8) -> bool,
+    deleter: Deleter,
 }
 
 // TODO: require <T: Send> in retire
 unsafe impl Send for Retired {}
 
 impl Retired {
-    pub(crate) fn new<T>(ptr: *mut T) -> Self {
+    /// A retired `Box<T>` made by `Box::into_raw`, freed by reconstructing the box, so that the
+    /// `Drop` of `T` runs. This is the deleter of `Thread::retire`.
+    pub fn new<T>(ptr: *mut T) -> Self {
+        Self::with(ptr as *mut u8, Deleter::boxed::<T>())
+    }
+
+    /// A retired `Box<T>` made by `Box::into_raw`, whose memory is freed without running the
+    /// `Drop` of `T`, e.g. for a node whose fields were moved out. Anything the node owns leaks.
+    pub fn new_no_drop<T>(ptr: *mut T) -> Self {
+        Self::with_layout(ptr as *mut u8, Layout::new::<T>())
+    }
+
+    pub(crate) fn with_deleter(ptr: *mut u8, deleter: unsafe fn(*mut u8)) -> Self {
+        Self::with(ptr, Deleter::Fn(deleter))
+    }
+
+    /// A hazard store replaced by a resize, freed by `deleter`.
+    pub(crate) fn store(ptr: *mut u8, deleter: unsafe fn(*mut u8)) -> Self {
+        Self {
+            internal: true,
+            ..Self::with_deleter(ptr, deleter)
+        }
+    }
+
+    fn with(ptr: *mut u8, deleter: Deleter) -> Self {
+        Self {
+            ptr,
+            deleter,
+            seq: 0,
+            epoch: 0,
+            hot: false,
+            grace: false,
+            internal: false,
+            #[cfg(feature = "debug-checks")]
+            survived: 0,
+        }
+    }
+
+    /// A deferred call of `f`, run when it is "freed".
+    pub(crate) fn deferred<F: FnOnce() + Send + 'static>(f: F, epoch: usize) -> Self {
         Self {
-            ptr: ptr as *mut u8,
-            deleter: free::<T>,
+            epoch,
+            grace: true,
+            internal: true,
+            ..Self::with_deleter(Box::into_raw(Box::new(f)) as *mut u8, run::<F>)
         }
     }
+
+    pub(crate) fn with_layout(ptr: *mut u8, layout: Layout) -> Self {
+        Self::with(ptr, Deleter::Dealloc(layout))
+    }
+
+    pub(crate) unsafe fn free(self) {
+        free_group(&mut [], self.deleter, [self.ptr])
+    }
 }
 
-impl<'domain> Unlinked<'domain> {
-    pub(crate) fn new<T: Invalidate>(ptrs: Vec<*mut T>, hps: Vec<HazardPointer<'domain>>) -> Self {
+impl<'domain, S: HazardStore> Unlinked<'domain, S> {
+    pub(crate) fn new<T: Invalidate>(
+        ptrs: Vec<*mut T>,
+        hps: Vec<HazardPointer<'domain, S>>,
+    ) -> Self {
         Self {
-            ptrs: unsafe { mem::transmute::<Vec<_>, Vec<*mut u8>>(ptrs) },
-            invalidater: invalidate::<T>,
-            deleter: free::<T>,
+            ptrs: UnlinkedPtrs {
+                ptrs: unsafe { mem::transmute::<Vec<_>, Vec<*mut u8>>(ptrs) },
+                invalidater: invalidate::<T>,
+                #[cfg(feature = "debug-checks")]
+                is_invalidated: is_invalidated::<T>,
+                deleter: Deleter::boxed::<T>(),
+            },
             hps,
         }
     }
 
-    pub(crate) fn do_invalidation(self) -> (Vec<Retired>, Vec<HazardPointer<'domain>>) {
+    pub(crate) fn do_invalidation(self) -> (Vec<Retired>, Vec<HazardPointer<'domain, S>>) {
+        (self.ptrs.do_invalidation(), self.hps)
+    }
+
+    /// Leaves the frontier protected by forgetting the hazard pointers, so that the pointers can
+    /// be invalidated after the owner is gone.
+    pub(crate) fn abandon(self) -> UnlinkedPtrs {
+        mem::forget(self.hps);
+        self.ptrs
+    }
+}
+
+impl UnlinkedPtrs {
+    pub(crate) fn do_invalidation(self) -> Vec<Retired> {
         let mut retireds = Vec::with_capacity(self.ptrs.len());
         for ptr in self.ptrs {
             unsafe { (self.invalidater)(ptr) };
-            retireds.push(Retired {
-                ptr,
-                deleter: self.deleter,
-            });
+            #[cfg(feature = "debug-checks")]
+            assert!(
+                unsafe { (self.is_invalidated)(ptr) },
+                "{:p} is not invalidated after `Invalidate::invalidate`",
+                ptr
+            );
+            retireds.push(Retired::with(ptr, self.deleter));
         }
-        (retireds, self.hps)
+        retireds
+    }
+
+    /// Frees the pointers without invalidating them, when no one can access them anymore.
+    pub(crate) unsafe fn free(self) {
+        free_group(&mut [], self.deleter, self.ptrs)
+    }
+}
+
+unsafe fn run<F: FnOnce()>(ptr: *mut u8) {
+    Box::from_raw(ptr as *mut F)()
+}
+
+unsafe fn drop_in_place<T>(ptr: *mut u8) {
+    ptr::drop_in_place(ptr as *mut T)
+}
+
+/// Per-thread cache of reclaimed nodes of a layout, reused by `Thread::alloc_node`.
+pub(crate) struct NodeCache {
+    layout: Layout,
+    pub(crate) cap: usize,
+    nodes: Vec<*mut u8>,
+}
+
+impl NodeCache {
+    pub(crate) fn new(layout: Layout, cap: usize) -> Self {
+        Self {
+            layout,
+            cap,
+            nodes: Vec::new(),
+        }
+    }
+
+    pub(crate) fn is_for(&self, layout: Layout) -> bool {
+        self.layout == layout
+    }
+
+    /// Whether `deleter` frees a `Box` of the layout of this cache.
+    fn matches(&self, deleter: Deleter) -> bool {
+        match deleter {
+            Deleter::Boxed { layout, .. } | Deleter::Dealloc(layout) => layout == self.layout,
+            Deleter::Fn(_) => false,
+        }
+    }
+
+    /// Drops and keeps the nodes taken from `ptrs` until the cache is full. `deleter` must be
+    /// `matches`.
+    unsafe fn recycle(&mut self, deleter: Deleter, ptrs: &mut impl Iterator<Item = *mut u8>) {
+        let drop = match deleter {
+            Deleter::Boxed { drop, .. } => Some(drop),
+            _ => None,
+        };
+        while self.nodes.len() < self.cap {
+            let Some(ptr) = ptrs.next() else {
+                return;
+            };
+            if let Some(drop) = drop {
+                drop(ptr);
+            }
+            self.nodes.push(ptr);
+        }
+    }
+
+    pub(crate) fn pop(&mut self) -> Option<*mut u8> {
+        self.nodes.pop()
     }
 }
 
-unsafe fn free<T>(ptr: *mut u8) {
-    drop(Box::from_raw(ptr as *mut T))
+impl Drop for NodeCache {
+    fn drop(&mut self) {
+        if self.layout.size() == 0 {
+            return;
+        }
+        for node in self.nodes.drain(..) {
+            unsafe { dealloc(node, self.layout) };
+        }
+    }
+}
+
+/// Frees `retireds`, or recycles them into the matching node caches. Hot entries are freed first.
+/// Unless `ordered`, they are partitioned by their deleter in one pass, so that each group is freed
+/// by a loop matching the deleter and the node caches once, whose indirect call is easy to predict.
+pub(crate) unsafe fn free_batch(
+    caches: &mut [NodeCache],
+    mut retireds: Vec<Retired>,
+    ordered: bool,
+) {
+    if ordered {
+        if retireds.iter().any(|r| r.hot) {
+            // Stable, to keep the order among the hot entries and among the others.
+            retireds.sort_by_key(|r| !r.hot);
+        }
+        for retired in retireds {
+            free_group(caches, retired.deleter, [retired.ptr]);
+        }
+        return;
+    }
+    // The groups in the order of their first entries, which is stable, with the hot ones first.
+    let mut groups: Vec<(bool, Deleter, Vec<*mut u8>)> = Vec::new();
+    let mut indices = FxHashMap::default();
+    for retired in retireds {
+        let index = *indices
+            .entry((!retired.hot, retired.deleter.key()))
+            .or_insert_with(|| {
+                groups.push((!retired.hot, retired.deleter, Vec::new()));
+                groups.len() - 1
+            });
+        groups[index].2.push(retired.ptr);
+    }
+    groups.sort_by_key(|(cold, ..)| *cold);
+    for (_, deleter, ptrs) in groups {
+        free_group(caches, deleter, ptrs);
+    }
+}
+
+/// Frees `ptrs`, all freed by `deleter`, or recycles them into the matching node cache.
+unsafe fn free_group(
+    caches: &mut [NodeCache],
+    deleter: Deleter,
+    ptrs: impl IntoIterator<Item = *mut u8>,
+) {
+    let mut ptrs = ptrs.into_iter();
+    if let Some(cache) = caches.iter_mut().find(|c| c.matches(deleter)) {
+        cache.recycle(deleter, &mut ptrs);
+    }
+    match deleter {
+        Deleter::Fn(deleter) => ptrs.for_each(|ptr| deleter(ptr)),
+        Deleter::Boxed { drop, layout } => ptrs.for_each(|ptr| {
+            drop(ptr);
+            dealloc_box(ptr, layout);
+        }),
+        Deleter::Dealloc(layout) => ptrs.for_each(|ptr| dealloc_box(ptr, layout)),
+    }
 }
 
 unsafe fn invalidate<T: Invalidate>(ptr: *mut u8) {
     T::invalidate(&*(ptr as *mut T))
 }
 
+#[cfg(feature = "debug-checks")]
+unsafe fn is_invalidated<T: Invalidate>(ptr: *mut u8) -> bool {
+    T::is_invalidated(&*(ptr as *mut T))
+}
+
 #[derive(Debug)]
 pub(crate) struct RetiredList {
     head: AtomicPtr<RetiredListNode>,
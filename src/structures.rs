@@ -0,0 +1,5 @@
+//! Reference implementations of concurrent data structures with HP++.
+
+mod queue;
+
+pub use self::queue::Queue;
@@ -0,0 +1,161 @@
+use core::mem::MaybeUninit;
+use core::ptr;
+use core::sync::atomic::{AtomicBool, AtomicPtr, Ordering};
+
+use crate::{HazardPointer, Invalidate, Thread, Unlink};
+
+/// Michael-Scott queue, whose dequeue unlinks the head with `try_unlink`.
+#[derive(Debug)]
+pub struct Queue<T> {
+    head: AtomicPtr<Node<T>>,
+    tail: AtomicPtr<Node<T>>,
+}
+
+#[derive(Debug)]
+struct Node<T> {
+    data: MaybeUninit<T>,
+    next: AtomicPtr<Node<T>>,
+    invalidated: AtomicBool,
+}
+
+unsafe impl<T: Send> Sync for Queue<T> {}
+unsafe impl<T: Send> Send for Queue<T> {}
+
+impl<T> Node<T> {
+    fn new(data: MaybeUninit<T>) -> *mut Self {
+        Box::into_raw(Box::new(Self {
+            data,
+            next: AtomicPtr::new(ptr::null_mut()),
+            invalidated: AtomicBool::new(false),
+        }))
+    }
+}
+
+impl<T> Invalidate for Node<T> {
+    fn invalidate(&self) {
+        self.invalidated.store(true, Ordering::Release);
+    }
+
+    fn is_invalidated(&self) -> bool {
+        self.invalidated.load(Ordering::Acquire)
+    }
+}
+
+/// Unlinks the sentinel `head`, making `next` the new sentinel.
+struct HeadUnlink<'q, T> {
+    queue: &'q Queue<T>,
+    head: *mut Node<T>,
+    next: *mut Node<T>,
+}
+
+impl<T> Unlink<Node<T>> for HeadUnlink<'_, T> {
+    fn do_unlink(&self) -> Result<Vec<*mut Node<T>>, ()> {
+        self.queue
+            .head
+            .compare_exchange(self.head, self.next, Ordering::Release, Ordering::Relaxed)
+            .map(|_| vec![self.head])
+            .map_err(|_| ())
+    }
+}
+
+impl<T> Default for Queue<T> {
+    fn default() -> Self {
+        let sentinel = Node::new(MaybeUninit::uninit());
+        Self {
+            head: AtomicPtr::new(sentinel),
+            tail: AtomicPtr::new(sentinel),
+        }
+    }
+}
+
+impl<T> Queue<T> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn push(&self, t: T, thread: &mut Thread<'_>) {
+        let new = Node::new(MaybeUninit::new(t));
+        let mut hp = HazardPointer::new(thread);
+
+        loop {
+            // SAFETY: `tail` is always a valid node that is not unlinked yet, as `try_pop` moves
+            // `tail` forward before unlinking the node it points to.
+            let tail = hp.protect(&self.tail);
+            let tail_ref = unsafe { &*tail };
+
+            let next = tail_ref.next.load(Ordering::Acquire);
+            if !next.is_null() {
+                let _ =
+                    self.tail
+                        .compare_exchange(tail, next, Ordering::Release, Ordering::Relaxed);
+                continue;
+            }
+
+            if tail_ref
+                .next
+                .compare_exchange(ptr::null_mut(), new, Ordering::Release, Ordering::Relaxed)
+                .is_ok()
+            {
+                let _ = self
+                    .tail
+                    .compare_exchange(tail, new, Ordering::Release, Ordering::Relaxed);
+                return;
+            }
+        }
+    }
+
+    /// Attempts to dequeue from the front.
+    ///
+    /// Returns `None` if the queue is empty.
+    pub fn try_pop(&self, thread: &mut Thread<'_>) -> Option<T> {
+        let mut head_hp = HazardPointer::new(thread);
+        let mut next_hp = HazardPointer::new(thread);
+
+        loop {
+            let head = head_hp.protect(&self.head);
+            let head_ref = unsafe { &*head };
+
+            // Protecting `next` is valid as long as `head` is not invalidated, even if it's
+            // already unlinked: then the unlinker protects `next` as the frontier.
+            let next =
+                match next_hp.protect_pp(head_ref, &head_ref.next, &|node| node.is_invalidated()) {
+                    Ok(next) => next,
+                    Err(()) => continue,
+                };
+            if next.is_null() {
+                return None;
+            }
+
+            // Move `tail` forward before unlinking the node it may point to.
+            let tail = self.tail.load(Ordering::Relaxed);
+            if tail == head {
+                let _ =
+                    self.tail
+                        .compare_exchange(tail, next, Ordering::Release, Ordering::Relaxed);
+            }
+
+            let unlink = HeadUnlink {
+                queue: self,
+                head,
+                next,
+            };
+            if unsafe { thread.try_unlink(unlink, &[next]) } {
+                // SAFETY: `next` is protected, and only the unlinker of `head` reads the data of
+                // the new sentinel.
+                return Some(unsafe { (*next).data.assume_init_read() });
+            }
+        }
+    }
+}
+
+impl<T> Drop for Queue<T> {
+    fn drop(&mut self) {
+        let sentinel = unsafe { Box::from_raw(*self.head.get_mut()) };
+        let mut curr = sentinel.next.load(Ordering::Relaxed);
+        while !curr.is_null() {
+            let curr_ref = unsafe { Box::from_raw(curr) };
+            drop(unsafe { curr_ref.data.assume_init() });
+            curr = curr_ref.next.load(Ordering::Relaxed);
+        }
+    }
+}
@@ -0,0 +1,10 @@
+//! The atomics and barriers that the reclamation protocol relies on, imported from one place so
+//! that they can be swapped for the instrumented ones of a model checker.
+//!
+//! There is no `loom` switch: loom's atomics can't be constructed in a const context, which
+//! `Domain::new`, the thread records and `DEFAULT_DOMAIN` rely on.
+
+pub(crate) use core::sync::atomic::{
+    fence, AtomicBool, AtomicPtr, AtomicU32, AtomicU64, AtomicUsize, Ordering,
+};
+pub(crate) use membarrier::{heavy, light};
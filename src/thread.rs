@@ -1,216 +1,981 @@
-use core::sync::atomic::{AtomicPtr, Ordering};
-use core::{mem, ptr};
-use std::collections::VecDeque;
+use alloc::collections::VecDeque;
+use core::alloc::Layout;
+use core::mem::{self, MaybeUninit};
+use core::ops::{Deref, DerefMut};
+use std::time::Instant;
+
+use crossbeam_utils::Backoff;
+use rustc_hash::FxHashSet;
 
 use crate::domain::Domain;
+use crate::domain::DomainConfig;
 use crate::domain::EpochBarrier;
-use crate::hazard::ThreadRecord;
-use crate::retire::{Retired, Unlinked};
+use crate::domain::Reclamation;
+use crate::hazard::{HazardArray, HazardStore, ThreadRecord, HAZARD_ARRAY_INIT_SIZE};
+use crate::retire::{free_batch, NodeCache, Retired, Unlinked};
+use crate::sync::{fence, AtomicPtr, Ordering};
 use crate::HazardPointer;
 use crate::{Invalidate, Unlink};
 
-pub struct Thread<'domain> {
-    pub(crate) domain: &'domain Domain,
-    pub(crate) hazards: &'domain ThreadRecord,
+pub struct Thread<'domain, S: HazardStore = HazardArray> {
+    pub(crate) domain: &'domain Domain<S>,
+    pub(crate) hazards: &'domain ThreadRecord<S>,
     /// available slots of hazard array
     pub(crate) available_indices: Vec<usize>,
     // Used for HP++
-    // TODO: only 2 entries required
-    pub(crate) epoched_hps: VecDeque<(usize, Vec<HazardPointer<'domain>>)>,
+    pub(crate) epoched_hps: EpochedHazards<'domain, S>,
+    /// The epoch read by the last invalidation.
+    epoch: usize,
     // Used for HP++. It's the thread-local `retireds` in the paper.
     // These should be invalidated and added to retireds.
-    pub(crate) unlinkeds: Vec<Unlinked<'domain>>,
+    pub(crate) unlinkeds: Vec<Unlinked<'domain, S>>,
     pub(crate) retired: Vec<Retired>,
     pub(crate) count: usize,
+    /// Reclamation cadence, read from the domain.
+    config: DomainConfig,
+    /// `Domain::config_version` when `config` was read.
+    config_version: *const DomainConfig,
+    /// User-defined label for diagnostics.
+    label: String,
+    node_caches: Vec<NodeCache>,
+    /// Flush the retired pointers once this many are buffered, regardless of `flush_interval`.
+    max_local_retired: usize,
+    /// Set by `suspend_collection`.
+    collection_suspended: bool,
+    /// Whether a reclamation was skipped while `collection_suspended`.
+    collection_deferred: bool,
+    /// Set by `set_retire_rate_limit`.
+    rate_limit: Option<RetireRateLimit>,
+    /// Reclaimed pointers whose freeing is spread over the following retirements, because they
+    /// were reclaimed while the retire rate exceeded the limit.
+    pending_frees: VecDeque<Retired>,
+    /// The guarded set of the last reclamation, kept to reuse its allocation.
+    guarded_ptrs: FxHashSet<*mut u8>,
+    #[cfg(feature = "metrics")]
+    slot_reuse: SlotReuse,
+}
+
+/// Reuse distances of the hazard slots of a thread, i.e. the number of acquires between releasing
+/// a slot and reacquiring it.
+#[cfg(feature = "metrics")]
+#[derive(Debug, Default)]
+struct SlotReuse {
+    acquires: usize,
+    /// The value of `acquires` when each slot was released last, or `usize::MAX` if never.
+    released_at: Vec<usize>,
+    histogram: [usize; 16],
+}
+
+/// Token bucket of `Thread::set_retire_rate_limit`, holding up to a second of retirements.
+#[derive(Debug)]
+struct RetireRateLimit {
+    per_sec: usize,
+    tokens: f64,
+    refilled_at: Instant,
+    /// Whether the last retirement found the bucket empty.
+    exceeded: bool,
+}
+
+impl RetireRateLimit {
+    fn new(per_sec: usize) -> Self {
+        Self {
+            per_sec,
+            tokens: per_sec as f64,
+            refilled_at: Instant::now(),
+            exceeded: false,
+        }
+    }
+
+    /// Take a token for each of `n` retirements, unless the bucket doesn't have enough.
+    fn consume(&mut self, n: usize) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.refilled_at).as_secs_f64();
+        self.refilled_at = now;
+        let per_sec = self.per_sec as f64;
+        self.tokens = (self.tokens + elapsed * per_sec).min(per_sec);
+        self.exceeded = self.tokens < n as f64;
+        if !self.exceeded {
+            self.tokens -= n as f64;
+        }
+    }
+}
+
+/// Number of pending frees freed by each retirement, more than the one it adds.
+const PENDING_FREES_PER_RETIRE: usize = 2;
+
+/// The hazard pointers protecting the frontiers of invalidated nodes (HP++), by the epoch of their
+/// invalidation, older first. They are released once two epochs have elapsed since then, so the
+/// remaining ones are from the current or the previous epoch, and two entries suffice.
+#[derive(Debug)]
+pub(crate) struct EpochedHazards<'domain, S: HazardStore> {
+    entries: [Option<(usize, Vec<HazardPointer<'domain, S>>)>; 2],
+}
+
+impl<'domain, S: HazardStore> EpochedHazards<'domain, S> {
+    const fn new() -> Self {
+        Self {
+            entries: [None, None],
+        }
+    }
+
+    /// Add the hazard pointers of an invalidation in `epoch`, after releasing the entries for
+    /// which two epochs have elapsed since, from the oldest.
+    fn push(&mut self, epoch: usize, mut hps: Vec<HazardPointer<'domain, S>>) {
+        while let Some((old_epoch, _)) = &self.entries[0] {
+            if !EpochBarrier::check(*old_epoch, epoch) {
+                break;
+            }
+            self.entries[0] = self.entries[1].take();
+        }
+        let last = if self.entries[1].is_some() { 1 } else { 0 };
+        match &mut self.entries[last] {
+            Some((last_epoch, last_hps)) if *last_epoch == epoch => last_hps.append(&mut hps),
+            None => self.entries[0] = Some((epoch, hps)),
+            Some(_) if last == 0 => self.entries[1] = Some((epoch, hps)),
+            Some(_) => {
+                // Not reached, as the epoch never goes back. Otherwise, moving the older hazard
+                // pointers to a newer entry only delays their release, which is safe.
+                let (_, mut older) = self.entries[0].take().unwrap();
+                let (newer_epoch, mut newer) = self.entries[1].take().unwrap();
+                newer.append(&mut older);
+                self.entries = [Some((newer_epoch, newer)), Some((epoch, hps))];
+            }
+        }
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.entries = [None, None];
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.entries.iter().all(Option::is_none)
+    }
 }
 
-impl<'domain> Thread<'domain> {
-    pub fn new(domain: &'domain Domain) -> Self {
-        let (thread, available_indices) = domain.threads.acquire();
+#[cfg(feature = "metrics")]
+impl SlotReuse {
+    fn on_acquire(&mut self, idx: usize) {
+        if let Some(&released) = self.released_at.get(idx) {
+            if released != usize::MAX {
+                let distance = self.acquires - released;
+                let bucket = (usize::BITS - distance.leading_zeros()) as usize;
+                self.histogram[bucket.min(self.histogram.len() - 1)] += 1;
+            }
+        }
+        self.acquires += 1;
+    }
+
+    fn on_release(&mut self, idx: usize) {
+        if idx >= self.released_at.len() {
+            self.released_at.resize(idx + 1, usize::MAX);
+        }
+        self.released_at[idx] = self.acquires;
+    }
+}
+
+impl<'domain, S: HazardStore> Thread<'domain, S> {
+    pub fn new(domain: &'domain Domain<S>) -> Self {
+        let (thread, available_indices) = domain.threads.acquire(domain.allocator);
+        domain.attached.fetch_add(1, Ordering::Relaxed);
         Self {
             domain,
             hazards: thread,
             available_indices,
-            epoched_hps: VecDeque::new(),
+            epoched_hps: EpochedHazards::new(),
+            epoch: domain.barrier.read(domain.config().epoch_read_spins),
             unlinkeds: Vec::new(),
             retired: Vec::new(),
             count: 0,
+            config: *domain.config(),
+            config_version: domain.config_version(),
+            label: String::new(),
+            node_caches: Vec::new(),
+            max_local_retired: usize::MAX,
+            collection_suspended: false,
+            collection_deferred: false,
+            rate_limit: None,
+            pending_frees: VecDeque::new(),
+            guarded_ptrs: FxHashSet::default(),
+            #[cfg(feature = "metrics")]
+            slot_reuse: SlotReuse::default(),
         }
     }
+
+    /// Attach a new thread to `domain`, adopting `retireds` detached from a thread of another
+    /// domain by `detach`. They are flushed to `domain`, and reclaimed under the protections of
+    /// its threads.
+    ///
+    /// # Safety
+    /// * The pointers of `retireds` are no longer accessed through their former domain, as its
+    ///   hazard pointers don't keep them from being freed anymore.
+    /// * The entries made by `Retired::new` or `Retired::new_no_drop` satisfy the requirements of
+    ///   `Thread::retire` as well.
+    pub unsafe fn adopt(domain: &'domain Domain<S>, mut retireds: Vec<Retired>) -> Self {
+        let thread = Self::new(domain);
+        if !retireds.is_empty() {
+            let epoch = domain.barrier.read(thread.config.epoch_read_spins);
+            for retired in &mut retireds {
+                // `defer` counted the grace period in the epochs of the former domain. Restarting
+                // it only delays the call.
+                if retired.grace {
+                    retired.epoch = epoch;
+                }
+            }
+            domain.flush(retireds, &thread.config);
+        }
+        thread
+    }
+
+    /// Detach this thread from its domain like dropping it, but return its retired pointers
+    /// instead of flushing them to the domain, e.g. to `adopt` them in another domain as a worker
+    /// moves between subsystems. The unlinked pointers are invalidated and returned as well.
+    ///
+    /// As when dropping the thread, its hazard pointers must be dropped before. The returned
+    /// pointers are leaked if they are not adopted.
+    pub fn detach(mut self) -> Vec<Retired> {
+        // Not through the flush cadence, which would flush some of them to the old domain.
+        self.invalidate_unlinkeds();
+        let retireds = mem::take(&mut self.retired);
+        #[cfg(feature = "debug-checks")]
+        self.domain.forget_retired(&retireds);
+        retireds
+    }
+
+    /// Tags this thread with a label (e.g. its role), shown in its `Debug` output.
+    pub fn set_label(&mut self, label: &str) {
+        self.label.clear();
+        self.label.push_str(label);
+    }
+
+    /// The label set by `set_label`. Empty by default.
+    pub fn label(&self) -> &str {
+        &self.label
+    }
+
+    /// The effective interval of reclaiming the retired pointers of the domain.
+    pub fn collect_interval(&self) -> usize {
+        self.config.collect_interval
+    }
+
+    /// The effective interval of flushing the thread-local retired pointers to the domain.
+    pub fn flush_interval(&self) -> usize {
+        self.config.flush_interval
+    }
+
+    /// The effective interval of invalidating the unlinked nodes.
+    pub fn invalidation_interval(&self) -> usize {
+        self.config.invalidation_interval
+    }
+
+    /// Flush the retired pointers of this thread to the domain whenever `n` of them are buffered,
+    /// in addition to every `flush_interval` retirements. This bounds `local_retired_len`.
+    pub fn set_max_local_retired(&mut self, n: usize) {
+        assert!(n > 0, "`max_local_retired` must be positive");
+        self.max_local_retired = n;
+    }
+
+    /// Limit the rate of retirements of this thread whose pointers are freed in bulk, to smooth
+    /// the pressure on the allocator under bursty retirement. While this thread retires more than
+    /// `per_sec` pointers per second, the pointers its reclamations find freeable are not freed at
+    /// once, but a few at each following retirement. `None` removes the limit.
+    pub fn set_retire_rate_limit(&mut self, per_sec: Option<usize>) {
+        assert!(per_sec != Some(0), "the retire rate limit must be positive");
+        self.rate_limit = per_sec.map(RetireRateLimit::new);
+    }
+
+    /// The limit set by `set_retire_rate_limit`, in retirements per second.
+    pub fn retire_rate_limit(&self) -> Option<usize> {
+        self.rate_limit.as_ref().map(|limit| limit.per_sec)
+    }
+
+    /// Number of reclaimed pointers of this thread whose freeing is spread over the following
+    /// retirements by the retire rate limit.
+    pub fn pending_frees(&self) -> usize {
+        self.pending_frees.len()
+    }
+
+    /// Number of retired pointers buffered in this thread, not flushed to the domain yet.
+    pub fn local_retired_len(&self) -> usize {
+        self.retired.len()
+    }
+
+    /// Histogram of the reuse distances of the hazard slots of this thread, i.e. the number of
+    /// hazard pointers acquired between releasing a slot and reacquiring it. Bucket 0 counts the
+    /// distance 0, bucket `i` the distances in `[2^(i-1), 2^i)`, and the last bucket also counts
+    /// all longer distances.
+    #[cfg(feature = "metrics")]
+    pub fn slot_reuse_distances(&self) -> &[usize; 16] {
+        &self.slot_reuse.histogram
+    }
 }
 
 // stuff related to reclamation
-impl<'domain> Thread<'domain> {
-    const COUNTS_BETWEEN_INVALIDATION: usize = 32;
-    const COUNTS_BETWEEN_FLUSH: usize = 64;
-    const COUNTS_BETWEEN_COLLECT: usize = 128;
-
-    fn flush_retireds(&mut self) {
+impl<'domain, S: HazardStore> Thread<'domain, S> {
+    pub(crate) fn flush_retireds(&mut self) {
         self.domain
-            .num_garbages
-            .fetch_add(self.retired.len(), Ordering::AcqRel);
-        self.domain.retireds.push(mem::take(&mut self.retired))
+            .flush(mem::take(&mut self.retired), &self.config)
     }
 
-    // NOTE: T: Send not required because we reclaim only locally.
+    // NOTE: T: Send not required because we reclaim only locally. Unlike `Domain::retire_global`.
     #[inline]
+    #[track_caller]
     pub unsafe fn retire<T>(&mut self, ptr: *mut T) {
-        self.retired.push(Retired::new(ptr));
-        let count = self.count.wrapping_add(1);
+        self.retire_many(&[ptr])
+    }
+
+    /// Retire all of `ptrs` like `retire`, e.g. the nodes of an unlinked subtree, checking the
+    /// flush and collect cadence once for the whole batch. If the batch reaches the cadence, it
+    /// flushes or collects once.
+    ///
+    /// # Safety
+    /// The same as `retire`, for each of `ptrs`.
+    #[inline]
+    #[track_caller]
+    pub unsafe fn retire_many<T>(&mut self, ptrs: &[*mut T]) {
+        #[cfg(feature = "debug-checks")]
+        for &ptr in ptrs {
+            self.domain.check_allocated(ptr.cast());
+            self.domain.check_retired_once(ptr);
+        }
+        self.push_retireds(ptrs.iter().map(|&ptr| Retired::new(ptr)))
+    }
+
+    /// Retire `ptr` like `retire`, hinting that freeing it promptly matters more than for other
+    /// pointers, e.g. it is a large object. The retired pointers of this thread are flushed and
+    /// reclaimed right away, and hot pointers are freed before the others in each reclamation.
+    ///
+    /// This costs a reclamation per call, so it is meant for objects whose memory outweighs it.
+    ///
+    /// # Safety
+    /// The same as `retire`.
+    #[inline]
+    #[track_caller]
+    pub unsafe fn retire_hot<T>(&mut self, ptr: *mut T) {
+        #[cfg(feature = "debug-checks")]
+        {
+            self.domain.check_allocated(ptr.cast());
+            self.domain.check_retired_once(ptr);
+        }
+        let mut retired = Retired::new(ptr);
+        retired.hot = true;
+        self.push_retired(retired)
+    }
+
+    /// Retire `ptr` like `retire`, to be freed by `deleter` instead of dropping it as a `Box`,
+    /// e.g. an object allocated by a custom allocator, or one whose teardown needs extra cleanup.
+    /// `deleter` is called exactly once with `ptr`, when it is reclaimed or the domain is dropped.
+    ///
+    /// Unlike `retire`, `ptr` is not checked against the allocation tracker of the domain.
+    ///
+    /// # Safety
+    /// The same as `retire`, except that `ptr` must be valid to pass to `deleter` instead of being
+    /// allocated by `Box`.
+    #[inline]
+    #[track_caller]
+    pub unsafe fn retire_with<T>(&mut self, ptr: *mut T, deleter: unsafe fn(*mut u8)) {
+        #[cfg(feature = "debug-checks")]
+        self.domain.check_retired_once(ptr);
+        self.push_retired(Retired::with_deleter(ptr.cast(), deleter))
+    }
+
+    /// Run `f` after a grace period, like `call_rcu`: in a reclamation of the domain (by any of its
+    /// threads) after the epoch has advanced twice since this call, i.e. two heavy barriers are
+    /// ordered after it. It runs when the domain is dropped at the latest.
+    ///
+    /// The grace period is not tied to hazard pointers: `f` may run while pointers retired
+    /// before this call are still protected.
+    #[inline]
+    pub fn defer<F: FnOnce() + Send + 'static>(&mut self, f: F) {
+        let epoch = self.domain.barrier.read(self.config.epoch_read_spins);
+        unsafe { self.push_retired(Retired::deferred(f, epoch)) }
+    }
+
+    /// Retire `ptr` allocated by `std::alloc::alloc` with `layout`, to be freed with
+    /// `std::alloc::dealloc`. Unlike `retire`, nothing is dropped.
+    ///
+    /// # Safety
+    /// * `ptr` is allocated by `std::alloc::alloc` with `layout`, and retired only once.
+    /// * `ptr` is unlinked from the data structure, so that it is not reachable anymore.
+    #[inline]
+    #[track_caller]
+    pub unsafe fn retire_raw_alloc(&mut self, ptr: *mut u8, layout: Layout) {
+        #[cfg(feature = "debug-checks")]
+        {
+            self.domain.check_allocated(ptr);
+            self.domain.check_retired_once(ptr);
+        }
+        self.push_retired(Retired::with_layout(ptr, layout))
+    }
+
+    #[inline]
+    #[track_caller]
+    pub(crate) unsafe fn push_retired(&mut self, retired: Retired) {
+        self.push_retireds([retired])
+    }
+
+    /// Push `retireds` to the retired pointers of this thread, and flush or collect once if the
+    /// batch reaches the cadence.
+    #[inline]
+    pub(crate) unsafe fn push_retireds(&mut self, retireds: impl IntoIterator<Item = Retired>) {
+        let start = self.retired.len();
+        self.retired.extend(retireds);
+        let n = self.retired.len() - start;
+        if n == 0 {
+            return;
+        }
+        #[cfg(feature = "retire-notify")]
+        self.domain
+            .notify_retired(self.retired[start..].iter().map(|r| r.ptr));
+        let hot = self.retired[start..].iter().any(|r| r.hot);
+        if let Some(limit) = &mut self.rate_limit {
+            limit.consume(n);
+        }
+        if !self.pending_frees.is_empty() {
+            self.free_pending(PENDING_FREES_PER_RETIRE * n);
+        }
+        let prev = self.count;
+        let count = prev.wrapping_add(n);
         self.count = count;
-        if count % Self::COUNTS_BETWEEN_FLUSH == 0 {
+        self.refresh_config();
+        // Whether the batch reached a multiple of `interval`, as a single retirement does.
+        let reaches = |interval: usize| count / interval != prev / interval || count < prev;
+        let over_watermark = self.domain.num_garbages() > self.config.max_garbage;
+        if hot
+            || over_watermark
+            || reaches(self.config.flush_interval)
+            || self.retired.len() >= self.max_local_retired
+        {
             self.flush_retireds();
         }
         // TODO: collecting right after pushing is kinda weird
-        if count % Self::COUNTS_BETWEEN_COLLECT == 0 {
-            self.do_reclamation();
+        if hot || over_watermark || reaches(self.config.collect_interval) {
+            self.collect();
         }
     }
 
+    /// Replace the object `src` points to with `make_new(old)`, e.g. an updated copy of it. `old`
+    /// is protected while `make_new` reads it. If `src` still points to `old`, it is swapped with
+    /// the new object and `old` is retired. Otherwise, the new object is dropped and this returns
+    /// `false`.
+    ///
+    /// # Safety
+    /// * `src` only points to objects allocated with `Box`, or null, and the objects are reachable
+    ///   only via `src`.
+    /// * `make_new` returns a pointer obtained from `Box::into_raw`, or null.
+    #[track_caller]
+    pub unsafe fn replace<T>(
+        &mut self,
+        src: &AtomicPtr<T>,
+        make_new: impl FnOnce(*mut T) -> *mut T,
+    ) -> bool {
+        let mut hp = HazardPointer::new(self);
+        let old = hp.protect(src);
+        let new = make_new(old);
+        let result = src.compare_exchange(old, new, Ordering::AcqRel, Ordering::Relaxed);
+        drop(hp);
+        if result.is_err() {
+            if !new.is_null() {
+                drop(Box::from_raw(new));
+            }
+            return false;
+        }
+        if !old.is_null() {
+            self.retire(old);
+        }
+        true
+    }
+
+    #[track_caller]
     pub unsafe fn try_unlink<T>(&mut self, unlink: impl Unlink<T>, frontier: &[*mut T]) -> bool
     where
         T: Invalidate,
     {
-        let hps: Vec<_> = frontier
-            .iter()
-            .map(|&ptr| {
-                let mut hp = HazardPointer::new(self);
-                hp.protect_raw(ptr);
-                hp
-            })
-            .collect();
+        let guard = UnlinkGuard::new(self, frontier);
+        let Ok(unlinkeds) = unlink.do_unlink() else {
+            return false;
+        };
+        guard.commit(unlinkeds);
 
-        if let Ok(unlinkeds) = unlink.do_unlink() {
-            self.unlinkeds.push(Unlinked::new(unlinkeds, hps));
+        let count = self.count.wrapping_add(1);
+        self.count = count;
+        self.refresh_config();
+        if count % self.config.invalidation_interval == 0 {
+            self.do_invalidation()
+        }
+        if count % self.config.flush_interval == 0 {
+            self.flush_retireds();
+        }
+        if count % self.config.collect_interval == 0 {
+            self.collect();
+        }
+        true
+    }
 
-            let count = self.count.wrapping_add(1);
-            self.count = count;
-            if count % Self::COUNTS_BETWEEN_INVALIDATION == 0 {
-                self.do_invalidation()
-            }
-            if count % Self::COUNTS_BETWEEN_FLUSH == 0 {
-                self.flush_retireds();
-            }
-            if count % Self::COUNTS_BETWEEN_COLLECT == 0 {
-                self.do_reclamation();
-            }
-            true
-        } else {
-            drop(hps);
-            false
+    /// Free up to `n` of the pending frees.
+    fn free_pending(&mut self, n: usize) {
+        let n = n.min(self.pending_frees.len());
+        let batch: Vec<_> = self.pending_frees.drain(..n).collect();
+        #[cfg(feature = "debug-checks")]
+        self.domain.forget_retired(&batch);
+        unsafe { free_batch(&mut self.node_caches, batch, self.config.fifo_reclamation) };
+    }
+
+    /// Read the configuration of the domain again if `Domain::apply_config` replaced it.
+    #[inline]
+    fn refresh_config(&mut self) {
+        let version = self.domain.config_version();
+        if version != self.config_version {
+            self.config = *self.domain.config();
+            self.config_version = version;
         }
     }
 
     pub(crate) fn do_invalidation(&mut self) {
-        let mut hps = Vec::with_capacity(2 * Self::COUNTS_BETWEEN_INVALIDATION);
-        let mut invalidateds = Vec::with_capacity(2 * Self::COUNTS_BETWEEN_INVALIDATION);
+        self.invalidate_unlinkeds();
+        if self.retired.len() >= self.max_local_retired {
+            self.flush_retireds();
+        }
+    }
+
+    /// Invalidate the unlinked pointers and move them to the retired pointers of this thread.
+    fn invalidate_unlinkeds(&mut self) {
+        let mut hps = Vec::with_capacity(2 * self.config.invalidation_interval);
+        let mut invalidateds = Vec::with_capacity(2 * self.config.invalidation_interval);
         for unlinked in self.unlinkeds.drain(..) {
             let (mut ptrs, mut hs) = unlinked.do_invalidation();
             invalidateds.append(&mut ptrs);
             hps.append(&mut hs);
         }
 
-        let epoch = self.domain.barrier.read();
-        while let Some(&(old_epoch, _)) = self.epoched_hps.front() {
-            if EpochBarrier::check(old_epoch, epoch) {
-                drop(self.epoched_hps.pop_front());
-            } else {
-                break;
-            }
-        }
-        self.epoched_hps.push_back((epoch, hps));
+        let epoch = self
+            .domain
+            .barrier
+            .read_cached(self.epoch, self.config.epoch_read_spins);
+        self.epoch = epoch;
+        self.epoched_hps.push(epoch, hps);
 
+        #[cfg(feature = "retire-notify")]
+        self.domain
+            .notify_retired(invalidateds.iter().map(|retired| retired.ptr));
         self.retired.append(&mut invalidateds);
     }
 
-    #[inline]
-    pub(crate) fn do_reclamation(&mut self) {
-        let retireds = self.domain.retireds.pop_all();
-        let retireds_len = retireds.len();
-        if retireds.is_empty() {
-            return;
+    /// Reclaim on the collection cadence, unless collection is suspended. The hazard array is
+    /// shrunk first if most of it is unused, e.g. after a bulk operation.
+    fn collect(&mut self) {
+        if self.collection_suspended {
+            self.collection_deferred = true;
+        } else {
+            self.shrink_array();
+            self.do_reclamation();
         }
+    }
 
-        self.domain.barrier.barrier();
+    /// Run a full reclamation cycle now, e.g. in a benchmark or on shutdown: invalidate the
+    /// unlinked nodes, flush the retired pointers of this thread, and reclaim the retired pointers
+    /// of the domain, even if collection is suspended. The pending frees of the retire rate limit
+    /// are freed as well. Returns the number of pointers freed.
+    ///
+    /// If nothing is retired, this returns 0 without a barrier.
+    pub fn force_reclaim(&mut self) -> usize {
+        if !self.unlinkeds.is_empty() {
+            self.do_invalidation();
+        }
+        self.flush_retireds();
+        let pending = self.pending_frees.len();
+        let reclaimed = self.do_reclamation();
+        self.free_pending(usize::MAX);
+        pending + reclaimed
+    }
+
+    /// Run `f` with a `Thread` of a temporary domain with the same configuration as this thread's,
+    /// e.g. to keep the transient garbage of a bulk rebuild from polluting the statistics of this
+    /// thread's domain and competing for its reclamation. When `f` returns, the temporary domain is
+    /// dropped, which frees all of its garbage.
+    ///
+    /// The pointers retired in `f` are freed regardless of the protections of other domains, so
+    /// they must not be reachable after `f` returns.
+    pub fn with_scratch_domain<R>(&mut self, f: impl FnOnce(&mut Thread<'_, S>) -> R) -> R {
+        let domain = Domain::<S>::with_hazard_store(self.config, self.domain.allocator);
+        let mut thread = Thread::new(&domain);
+        let result = f(&mut thread);
+        drop(thread);
+        result
+    }
+
+    /// Suspend the reclamations that `retire` and `try_unlink` start every `collect_interval`
+    /// calls, e.g. to keep their pauses out of a latency-critical section, until the returned
+    /// guard is dropped. Retiring through the guard still flushes and invalidates. If a
+    /// reclamation was skipped, dropping the guard runs it.
+    pub fn suspend_collection(&mut self) -> CollectionGuard<'_, 'domain, S> {
+        let was_suspended = mem::replace(&mut self.collection_suspended, true);
+        CollectionGuard {
+            thread: self,
+            was_suspended,
+        }
+    }
+
+    /// Reclaim the retired pointers of the domain, and return the number of those freed (or
+    /// left to the pending frees).
+    #[inline]
+    pub(crate) fn do_reclamation(&mut self) -> usize {
+        let Some(mut reclamation) = Reclamation::begin(self.domain, &self.config) else {
+            return 0;
+        };
 
         // only for hp++, but this doesn't introduce big cost for plain hp.
         self.epoched_hps.clear();
 
-        let guarded_ptrs = self.domain.collect_guarded_ptrs(self);
-        let not_freed: Vec<Retired> = retireds
-            .into_iter()
-            .filter_map(|element| {
-                if guarded_ptrs.contains(&element.ptr) {
-                    Some(element)
-                } else {
-                    unsafe { (element.deleter)(element.ptr) };
-                    None
-                }
-            })
-            .collect();
-        self.domain
-            .num_garbages
-            .fetch_sub(retireds_len - not_freed.len(), Ordering::AcqRel);
-        self.domain.retireds.push(not_freed);
+        let mut guarded_ptrs = mem::take(&mut self.guarded_ptrs);
+        let tag_mask = self.domain.config().tag_mask();
+        self.domain.collect_guarded_ptrs_masked(
+            &self.hazards.scan_hazard,
+            &mut guarded_ptrs,
+            tag_mask,
+        );
+        let to_free = reclamation.sift(&guarded_ptrs, tag_mask, &self.config);
+        if self.rate_limit.as_ref().is_some_and(|limit| limit.exceeded) {
+            self.pending_frees.extend(to_free);
+        } else {
+            #[cfg(feature = "debug-checks")]
+            self.domain.forget_retired(&to_free);
+            unsafe { free_batch(&mut self.node_caches, to_free, self.config.fifo_reclamation) };
+        }
+        let freed = reclamation.finish(guarded_ptrs.len(), &self.config);
+        self.guarded_ptrs = guarded_ptrs;
+        freed
+    }
+}
+
+// stuff related to node caches
+impl<'domain, S: HazardStore> Thread<'domain, S> {
+    /// Keep up to `cap` nodes of the layout of `T` reclaimed by this thread, instead of freeing
+    /// them, so that `alloc_node::<T>` can reuse them. The nodes freed as a `Box` or with
+    /// `std::alloc::dealloc` are cached: those of `retire` and its variants, of `try_unlink`, and
+    /// of `retire_raw_alloc`. The others, e.g. of `retire_with`, are freed by their deleter.
+    ///
+    /// Caches are matched by layout, so the types of the same size and alignment share one, and
+    /// the last `cap` registered for them applies.
+    pub fn register_node_cache<T>(&mut self, cap: usize) {
+        let layout = Layout::new::<T>();
+        match self.node_caches.iter_mut().find(|c| c.is_for(layout)) {
+            Some(cache) => cache.cap = cap,
+            None => self.node_caches.push(NodeCache::new(layout, cap)),
+        }
+    }
+
+    /// Allocate a node of type `T`, reusing a cached node if available.
+    pub fn alloc_node<T>(&mut self) -> Box<MaybeUninit<T>> {
+        let cached = self
+            .node_caches
+            .iter_mut()
+            .find(|c| c.is_for(Layout::new::<T>()))
+            .and_then(NodeCache::pop);
+        match cached {
+            Some(node) => unsafe { Box::from_raw(node as *mut MaybeUninit<T>) },
+            None => Box::new_uninit(),
+        }
     }
 }
 
 // stuff related to hazards
-impl<'domain> Thread<'domain> {
+impl<'domain, S: HazardStore> Thread<'domain, S> {
     /// acquire hazard slot
     pub(crate) fn acquire(&mut self) -> usize {
-        if let Some(idx) = self.available_indices.pop() {
-            idx
-        } else {
-            self.grow_array();
-            self.acquire()
+        self.try_acquire(false).unwrap()
+    }
+
+    /// acquire hazard slot, failing if `capped` and growing the hazard array would exceed
+    /// `max_hazard_bytes`.
+    pub(crate) fn try_acquire(&mut self, capped: bool) -> Option<usize> {
+        let (idx, old_array) = match self.available_indices.pop() {
+            Some(idx) => (idx, None),
+            None => {
+                let old_array = self.grow_array(capped)?;
+                (self.available_indices.pop().unwrap(), Some(old_array))
+            }
+        };
+        #[cfg(feature = "metrics")]
+        self.slot_reuse.on_acquire(idx);
+        // Only the owner modifies the counter, so it doesn't need a read-modify-write.
+        let active = &self.hazards.active_hazards;
+        active.store(active.load(Ordering::Relaxed) + 1, Ordering::Relaxed);
+        // Retire the old array only after the acquire is complete, because retiring may start a
+        // reclamation, which acquires hazard pointers and scans the hazard arrays itself.
+        if let Some(old_array) = old_array {
+            unsafe { self.push_retired(Retired::store(old_array.cast(), S::dealloc)) };
         }
+        Some(idx)
     }
 
-    fn grow_array(&mut self) {
+    /// Double the hazard array, returning the old one to be retired by the caller, or `None` if
+    /// `capped` and it would exceed `max_hazard_bytes`.
+    fn grow_array(&mut self, capped: bool) -> Option<*mut S> {
         let array_ptr = self.hazards.hazptrs.load(Ordering::Relaxed);
         let array = unsafe { &*array_ptr };
         let size = array.len();
         let new_size = size * 2;
-        let mut new_array = Box::new(Vec::with_capacity(new_size));
-        for i in 0..size {
-            new_array.push(AtomicPtr::new(array[i].load(Ordering::Relaxed)));
+
+        let added = S::size_of(new_size) - S::size_of(size);
+        let hazard_bytes = &self.domain.threads.hazard_bytes;
+        let total = hazard_bytes.fetch_add(added, Ordering::Relaxed) + added;
+        if capped && total > self.config.max_hazard_bytes {
+            hazard_bytes.fetch_sub(added, Ordering::Relaxed);
+            return None;
         }
-        for _ in size..new_size {
-            new_array.push(AtomicPtr::new(ptr::null_mut()));
+
+        let new_array_ptr = array.resize(new_size, self.domain.allocator);
+        self.hazards.hazptrs.store(new_array_ptr, Ordering::Release);
+        self.available_indices.extend(size..new_size);
+        Some(array_ptr)
+    }
+
+    /// Halve the hazard array while at least 3/4 of its slots are available, all of them in the
+    /// upper half, down to the initial size. The old arrays are retired like in `grow_array`.
+    fn shrink_array(&mut self) {
+        loop {
+            let array_ptr = self.hazards.hazptrs.load(Ordering::Relaxed);
+            let array = unsafe { &*array_ptr };
+            let size = array.len();
+            let new_size = size / 2;
+            if new_size < HAZARD_ARRAY_INIT_SIZE || self.available_indices.len() * 4 < size * 3 {
+                return;
+            }
+            // Hazard pointers keep their indices, so the slots in use must be in the lower half.
+            let upper_available = self
+                .available_indices
+                .iter()
+                .filter(|&&idx| idx >= new_size)
+                .count();
+            if upper_available < size - new_size {
+                return;
+            }
+
+            let new_array_ptr = array.resize(new_size, self.domain.allocator);
+            self.hazards.hazptrs.store(new_array_ptr, Ordering::Release);
+            self.available_indices.retain(|&idx| idx < new_size);
+            self.domain
+                .threads
+                .hazard_bytes
+                .fetch_sub(S::size_of(size) - S::size_of(new_size), Ordering::Relaxed);
+            // Not through `push_retired`, which may start the reclamation that calls this.
+            self.retired
+                .push(Retired::store(array_ptr.cast(), S::dealloc));
         }
-        self.hazards
-            .hazptrs
-            .store(Box::into_raw(new_array), Ordering::Release);
-        unsafe { self.retire(array_ptr) };
-        self.available_indices.extend(size..new_size)
     }
 
     /// release hazard slot
     pub(crate) fn release(&mut self, idx: usize) {
+        #[cfg(feature = "metrics")]
+        self.slot_reuse.on_release(idx);
         self.available_indices.push(idx);
+        let active = &self.hazards.active_hazards;
+        active.store(active.load(Ordering::Relaxed) - 1, Ordering::Release);
+    }
+
+    /// Number of hazard pointers of this thread, including those kept by HP++ invalidation,
+    /// whether or not they protect a pointer.
+    pub fn active_hazards(&self) -> usize {
+        let store = unsafe { &*self.hazards.hazptrs.load(Ordering::Relaxed) };
+        store.len() - self.available_indices.len()
+    }
+
+    /// The pointers protected by the hazard pointers of this thread, e.g. to find the one keeping
+    /// a retired pointer from being freed right before a reclamation.
+    pub fn guarded_ptrs(&self) -> Vec<*mut u8> {
+        // Only the owner replaces the store, so it can't be freed meanwhile.
+        let store = unsafe { &*self.hazards.hazptrs.load(Ordering::Relaxed) };
+        store.iter_protected().collect()
+    }
+
+    /// Protect all of `ptrs`, e.g. the frontier of `try_unlink`, with a single fence instead of an
+    /// ordered store per pointer. The caller validates the whole frontier afterwards.
+    pub fn protect_frontier<T>(&mut self, ptrs: &[*mut T]) -> Vec<HazardPointer<'domain, S>> {
+        let hps = ptrs
+            .iter()
+            .map(|&ptr| {
+                let mut hp = HazardPointer::new(self);
+                hp.protect_raw_relaxed(ptr);
+                hp
+            })
+            .collect();
+        // This is as strong as a release store per pointer. A release store only orders the
+        // accesses before it, so in both cases a thread that acquires a store made after the
+        // protections (e.g. the unlinking CAS) observes all of them. Reclaimers don't rely on
+        // this ordering at all: they observe the protections through the heavy barrier.
+        fence(Ordering::Release);
+        hps
+    }
+
+    /// Get protected pointers from `a` and `b` that the sources held at once, e.g. a node and its
+    /// successor: both are protected, then both sources are reloaded, and the protection of both
+    /// is retried if either changed. Unless a source changes and changes back meanwhile, there is
+    /// a moment between the loads and the reloads when `a` and `b` held the returned pointers.
+    ///
+    /// A retry overwrites both hazard pointers, so a stale protection doesn't keep unrelated
+    /// garbage from being freed.
+    pub fn protect_pair<T>(
+        &mut self,
+        a: &AtomicPtr<T>,
+        b: &AtomicPtr<T>,
+    ) -> (
+        HazardPointer<'domain, S>,
+        HazardPointer<'domain, S>,
+        *mut T,
+        *mut T,
+    ) {
+        let mut hp_a = HazardPointer::new(self);
+        let mut hp_b = HazardPointer::new(self);
+        let backoff = Backoff::new();
+        let mut ptr_a = a.load(Ordering::Relaxed);
+        let mut ptr_b = b.load(Ordering::Relaxed);
+        loop {
+            hp_a.protect_raw(ptr_a);
+            hp_b.protect_raw(ptr_b);
+            self.domain.barrier.light();
+            let new_a = a.load(Ordering::Acquire);
+            let new_b = b.load(Ordering::Acquire);
+            if new_a == ptr_a && new_b == ptr_b {
+                return (hp_a, hp_b, ptr_a, ptr_b);
+            }
+            ptr_a = new_a;
+            ptr_b = new_b;
+            backoff.spin();
+        }
+    }
+}
+
+impl<'domain, S: HazardStore> Thread<'domain, S> {
+    /// Leaves the unlinked pointers to `Domain::reclaim_abandoned`, instead of invalidating them
+    /// with user code while panicking. The record stays unavailable, keeping their frontier
+    /// protected.
+    fn abandon(&mut self) {
+        let unlinkeds = self
+            .unlinkeds
+            .drain(..)
+            .map(Unlinked::abandon)
+            .collect::<Vec<_>>();
+        // Not to run destructors while panicking, the pending frees are left to the reclamations
+        // of the domain, which will find them unguarded again.
+        self.retired.extend(self.pending_frees.drain(..));
+        self.flush_retireds();
+        self.domain.heavy_barrier();
+        self.epoched_hps.clear();
+        self.available_indices.clear();
+        self.hazards
+            .abandoned
+            .store(Box::into_raw(Box::new(unlinkeds)), Ordering::Release);
     }
 }
 
-impl<'domain> Drop for Thread<'domain> {
+/// Keeps the collection of a thread suspended while alive. See `Thread::suspend_collection`.
+#[derive(Debug)]
+pub struct CollectionGuard<'t, 'domain, S: HazardStore = HazardArray> {
+    thread: &'t mut Thread<'domain, S>,
+    was_suspended: bool,
+}
+
+impl<'domain, S: HazardStore> Deref for CollectionGuard<'_, 'domain, S> {
+    type Target = Thread<'domain, S>;
+
+    fn deref(&self) -> &Self::Target {
+        self.thread
+    }
+}
+
+impl<S: HazardStore> DerefMut for CollectionGuard<'_, '_, S> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.thread
+    }
+}
+
+impl<S: HazardStore> Drop for CollectionGuard<'_, '_, S> {
+    fn drop(&mut self) {
+        if self.was_suspended {
+            return;
+        }
+        self.thread.collection_suspended = false;
+        if mem::take(&mut self.thread.collection_deferred) {
+            self.thread.do_reclamation();
+        }
+    }
+}
+
+/// The protection of the frontier of an unlink in progress. If the unlink fails, or panics, dropping
+/// the guard releases the frontier. If it succeeds, `commit` keeps the frontier protected with the
+/// unlinked pointers until their invalidation.
+pub(crate) struct UnlinkGuard<'t, 'domain, S: HazardStore> {
+    thread: &'t mut Thread<'domain, S>,
+    hps: Vec<HazardPointer<'domain, S>>,
+}
+
+impl<'t, 'domain, S: HazardStore> UnlinkGuard<'t, 'domain, S> {
+    pub(crate) fn new<T>(thread: &'t mut Thread<'domain, S>, frontier: &[*mut T]) -> Self {
+        let hps = thread.protect_frontier(frontier);
+        Self { thread, hps }
+    }
+
+    /// Record `unlinkeds` as unlinked by the thread, to be invalidated with the frontier
+    /// protected.
+    pub(crate) fn commit<T: Invalidate>(self, unlinkeds: Vec<*mut T>) {
+        #[cfg(feature = "debug-checks")]
+        for &ptr in &unlinkeds {
+            self.thread.domain.check_allocated(ptr.cast());
+        }
+        self.thread
+            .unlinkeds
+            .push(Unlinked::new(unlinkeds, self.hps));
+    }
+}
+
+impl<S: HazardStore> Drop for Thread<'_, S> {
     fn drop(&mut self) {
+        let panicking = std::thread::panicking();
+        if panicking && !self.unlinkeds.is_empty() {
+            self.abandon();
+            self.domain.attached.fetch_sub(1, Ordering::AcqRel);
+            return;
+        }
+        if panicking {
+            // As in `abandon`, no destructors run while panicking.
+            self.retired.extend(self.pending_frees.drain(..));
+        }
         self.do_invalidation();
         self.flush_retireds();
-        self.domain.barrier.barrier();
+        self.domain.heavy_barrier();
         self.epoched_hps.clear();
         assert!(self.unlinkeds.is_empty());
         assert!(self.retired.is_empty());
         assert!(self.epoched_hps.is_empty());
+        self.free_pending(usize::MAX);
+        // Threads attaching concurrently are handled like in any reclamation, as their hazard
+        // pointers are collected. After this, dropping the domain only frees what is left.
+        if self.domain.attached.fetch_sub(1, Ordering::AcqRel) == 1
+            && self.config.reclaim_on_detach
+            && !panicking
+        {
+            // The entries in their grace period (`two_epoch_grace`, `defer`) are freed after the
+            // epoch advances twice, once in each reclamation.
+            for _ in 0..3 {
+                self.do_reclamation();
+                if self.domain.num_garbages() == 0 {
+                    break;
+                }
+            }
+            self.free_pending(usize::MAX);
+        }
         // WARNING: Dropping HazardPointer touches available_indices. So available_indices MUST be
-        // dropped after hps. For the same reason, Thread::drop MUST NOT acquire HazardPointer.
+        // dropped after hps. For the same reason, Thread::drop MUST NOT acquire HazardPointer from
+        // here on.
         self.available_indices.clear();
         self.domain.threads.release(self.hazards);
     }
 }
 
-impl core::fmt::Debug for Thread<'_> {
+impl<S: HazardStore> core::fmt::Debug for Thread<'_, S> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("Thread")
+            .field("label", &self.label)
             .field("domain", &(&self.domain as *const _))
             .field("hazards", &(&self.hazards as *const _))
             .field("available_indices", &self.available_indices.as_ptr())
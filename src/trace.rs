@@ -0,0 +1,149 @@
+//! Reclamation events in the Chrome Trace Event format, which Perfetto and `chrome://tracing` load.
+
+use core::cell::Cell;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::io::Write;
+use std::sync::Mutex;
+use std::time::Instant;
+
+pub(crate) struct Tracer {
+    enabled: AtomicBool,
+    output: Mutex<Option<TraceOutput>>,
+}
+
+struct TraceOutput {
+    writer: Box<dyn Write + Send>,
+    /// The origin of the timestamps.
+    start: Instant,
+    /// Whether no event is written yet, i.e. the next one is not preceded by a comma.
+    first: bool,
+}
+
+thread_local! {
+    static TID: Cell<usize> = const { Cell::new(0) };
+}
+
+/// Identifies the OS thread writing an event, as `std::thread::ThreadId` can't be formatted as a
+/// number.
+fn tid() -> usize {
+    static NEXT_TID: AtomicUsize = AtomicUsize::new(1);
+    TID.with(|tid| {
+        if tid.get() == 0 {
+            tid.set(NEXT_TID.fetch_add(1, Ordering::Relaxed));
+        }
+        tid.get()
+    })
+}
+
+impl Tracer {
+    pub(crate) const fn new() -> Self {
+        Self {
+            enabled: AtomicBool::new(false),
+            output: Mutex::new(None),
+        }
+    }
+
+    /// Start writing events to `writer`, finishing the previous writer if any.
+    pub(crate) fn start(&self, writer: Box<dyn Write + Send>) {
+        let mut output = self.output.lock().unwrap();
+        if let Some(prev) = output.take() {
+            prev.finish();
+        }
+        let mut new = TraceOutput {
+            writer,
+            start: Instant::now(),
+            first: true,
+        };
+        let _ = new.writer.write_all(b"[\n");
+        *output = Some(new);
+        self.enabled.store(true, Ordering::Relaxed);
+    }
+
+    /// Stop writing events, terminating the JSON array.
+    pub(crate) fn stop(&self) {
+        self.enabled.store(false, Ordering::Relaxed);
+        if let Some(output) = self.output.lock().unwrap().take() {
+            output.finish();
+        }
+    }
+
+    /// The start time of a complete event, or `None` if tracing is disabled.
+    #[inline]
+    pub(crate) fn begin(&self) -> Option<Instant> {
+        if self.enabled.load(Ordering::Relaxed) {
+            Some(Instant::now())
+        } else {
+            None
+        }
+    }
+
+    /// Write a complete event `name` that began at `begin`, as returned by `begin`.
+    pub(crate) fn complete(&self, name: &str, begin: Option<Instant>, args: &[(&str, usize)]) {
+        if let Some(begin) = begin {
+            let dur = begin.elapsed();
+            self.write(name, "X", Some(begin), Some(dur.as_secs_f64()), args);
+        }
+    }
+
+    /// Write an instant event `name` of the current thread.
+    pub(crate) fn instant(&self, name: &str, args: &[(&str, usize)]) {
+        if self.enabled.load(Ordering::Relaxed) {
+            self.write(name, "i", None, None, args);
+        }
+    }
+
+    fn write(
+        &self,
+        name: &str,
+        phase: &str,
+        begin: Option<Instant>,
+        dur_secs: Option<f64>,
+        args: &[(&str, usize)],
+    ) {
+        let mut output = self.output.lock().unwrap();
+        let Some(output) = output.as_mut() else {
+            return;
+        };
+        let begin = begin.unwrap_or_else(Instant::now);
+        let ts = begin.saturating_duration_since(output.start).as_secs_f64() * 1e6;
+        let mut event = format!(
+            r#"{{"name":"{}","cat":"hp_pp","ph":"{}","ts":{:.3},"pid":{},"tid":{}"#,
+            name,
+            phase,
+            ts,
+            std::process::id(),
+            tid()
+        );
+        match dur_secs {
+            Some(dur) => event.push_str(&format!(r#","dur":{:.3}"#, dur * 1e6)),
+            None => event.push_str(r#","s":"t""#),
+        }
+        event.push_str(r#","args":{"#);
+        for (i, (key, value)) in args.iter().enumerate() {
+            if i > 0 {
+                event.push(',');
+            }
+            event.push_str(&format!(r#""{}":{}"#, key, value));
+        }
+        event.push_str("}}");
+
+        let separator: &[u8] = if output.first { b"" } else { b",\n" };
+        output.first = false;
+        // Tracing is best-effort: a failing writer doesn't fail reclamation.
+        let _ = output.writer.write_all(separator);
+        let _ = output.writer.write_all(event.as_bytes());
+    }
+}
+
+impl TraceOutput {
+    fn finish(mut self) {
+        let _ = self.writer.write_all(b"\n]\n");
+        let _ = self.writer.flush();
+    }
+}
+
+impl Drop for Tracer {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
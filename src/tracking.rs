@@ -0,0 +1,82 @@
+use core::alloc::{GlobalAlloc, Layout};
+use core::cell::Cell;
+use std::alloc::System;
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+use std::thread_local;
+
+/// Tracker of the live allocations, consulted by the domains it is set to with
+/// `Domain::set_allocation_tracker`.
+pub trait AllocationTracker: Sync {
+    /// Whether `ptr` points into a live allocation.
+    fn is_allocated(&self, ptr: *mut u8) -> bool;
+}
+
+/// Global allocator wrapper that tracks the live allocations of `A`, so that retiring a pointer
+/// that is not allocated by it (e.g. a stack pointer or an already freed one) panics.
+///
+/// It is meant to be installed with `#[global_allocator]`, which makes every allocation lock a
+/// map of the live allocations. So it is only for development.
+#[derive(Debug)]
+pub struct TrackingAllocator<A = System> {
+    inner: A,
+    /// Sizes of the live allocations, by their start address.
+    live: Mutex<BTreeMap<usize, usize>>,
+}
+
+thread_local! {
+    /// Whether this thread is updating `live`, whose own allocations are not tracked.
+    static IN_TRACKER: Cell<bool> = const { Cell::new(false) };
+}
+
+impl<A> TrackingAllocator<A> {
+    pub const fn new(inner: A) -> Self {
+        Self {
+            inner,
+            live: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// Total size of the live allocations, e.g. to check for leaks.
+    pub fn live_bytes(&self) -> usize {
+        self.untracked(|live| live.values().sum())
+            .unwrap_or_default()
+    }
+
+    fn untracked<R>(&self, f: impl FnOnce(&mut BTreeMap<usize, usize>) -> R) -> Option<R> {
+        if IN_TRACKER.try_with(|t| t.replace(true)).unwrap_or(true) {
+            return None;
+        }
+        let result = f(&mut self.live.lock().unwrap_or_else(|e| e.into_inner()));
+        let _ = IN_TRACKER.try_with(|t| t.set(false));
+        Some(result)
+    }
+}
+
+unsafe impl<A: GlobalAlloc> GlobalAlloc for TrackingAllocator<A> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = self.inner.alloc(layout);
+        if !ptr.is_null() {
+            self.untracked(|live| live.insert(ptr as usize, layout.size()));
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.untracked(|live| live.remove(&(ptr as usize)));
+        self.inner.dealloc(ptr, layout)
+    }
+}
+
+impl<A: Sync> AllocationTracker for TrackingAllocator<A> {
+    fn is_allocated(&self, ptr: *mut u8) -> bool {
+        let addr = ptr as usize;
+        // If called while updating the map, e.g. from a panic hook, don't report a false positive.
+        self.untracked(|live| {
+            live.range(..=addr)
+                .next_back()
+                .is_some_and(|(&start, &size)| addr < start + size.max(1))
+        })
+        .unwrap_or(true)
+    }
+}
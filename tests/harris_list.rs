@@ -80,6 +80,10 @@ impl<K, V> hp_pp::Invalidate for Node<K, V> {
         let next = self.next.load(Ordering::Acquire);
         self.next.store(tagged(next, 1 | 2), Ordering::Release);
     }
+
+    fn is_invalidated(&self) -> bool {
+        tag(self.next.load(Ordering::Acquire)) & 2 == 2
+    }
 }
 
 /// Physical unlink in the traverse function of Harris's list
@@ -0,0 +1,66 @@
+#![cfg(feature = "structures")]
+
+use std::thread::scope;
+
+use hp_pp::structures::Queue;
+use hp_pp::*;
+
+#[test]
+fn queue_mpmc() {
+    const THREADS: usize = 8;
+    const ITER: usize = 1024 * 16;
+
+    let domain = Domain::new();
+    let queue = Queue::new();
+    let sums: Vec<usize> = scope(|s| {
+        let handles: Vec<_> = (0..THREADS)
+            .map(|_| {
+                s.spawn(|| {
+                    let mut thread = Thread::new(&domain);
+                    let mut sum = 0;
+                    for i in 0..ITER {
+                        queue.push(i, &mut thread);
+                        sum += queue.try_pop(&mut thread).unwrap();
+                    }
+                    sum
+                })
+            })
+            .collect();
+        handles.into_iter().map(|h| h.join().unwrap()).collect()
+    });
+    assert_eq!(sums.iter().sum::<usize>(), THREADS * ITER * (ITER - 1) / 2);
+
+    let mut thread = Thread::new(&domain);
+    assert!(queue.try_pop(&mut thread).is_none());
+}
+
+#[test]
+fn queue_producers_consumers() {
+    const THREADS: usize = 4;
+    const ITER: usize = 1024 * 16;
+
+    let domain = Domain::new();
+    let queue = Queue::new();
+    scope(|s| {
+        for _ in 0..THREADS {
+            s.spawn(|| {
+                let mut thread = Thread::new(&domain);
+                for i in 0..ITER {
+                    queue.push(Box::new(i), &mut thread);
+                }
+            });
+            s.spawn(|| {
+                let mut thread = Thread::new(&domain);
+                let mut popped = 0;
+                while popped < ITER {
+                    if queue.try_pop(&mut thread).is_some() {
+                        popped += 1;
+                    }
+                }
+            });
+        }
+    });
+
+    let mut thread = Thread::new(&domain);
+    assert!(queue.try_pop(&mut thread).is_none());
+}
@@ -1,4 +1,5 @@
 use core::sync::atomic::{AtomicPtr, Ordering::*};
+use std::sync::Mutex;
 use std::thread::sleep;
 use std::time::Duration;
 
@@ -7,6 +8,41 @@ use queue::Queue;
 use stack::Stack;
 use std::thread::scope;
 
+/// The ids of the dropped `DropNode`s of a test, in the order of the drops. Each test leaks its
+/// own, as the tests run concurrently.
+#[derive(Default)]
+struct Drops(Mutex<Vec<usize>>);
+
+impl Drops {
+    fn new() -> &'static Self {
+        Box::leak(Box::default())
+    }
+
+    /// A boxed node recording its drop with `id`.
+    fn node(&'static self, id: usize) -> *mut DropNode {
+        Box::into_raw(Box::new(DropNode { id, drops: self }))
+    }
+
+    fn count(&self) -> usize {
+        self.0.lock().unwrap().len()
+    }
+
+    fn ids(&self) -> Vec<usize> {
+        self.0.lock().unwrap().clone()
+    }
+}
+
+struct DropNode {
+    id: usize,
+    drops: &'static Drops,
+}
+
+impl Drop for DropNode {
+    fn drop(&mut self) {
+        self.drops.0.lock().unwrap().push(self.id);
+    }
+}
+
 #[test]
 fn counter() {
     const THREADS: usize = 4;
@@ -158,6 +194,1812 @@ fn stack_queue() {
     assert!(stack.try_pop().is_none());
 }
 
+#[test]
+fn quiesce() {
+    let domain = Domain::new();
+    let mut thread = Thread::new(&domain);
+    assert!(domain.is_quiescent());
+    assert!(domain.quiesce(Duration::ZERO));
+
+    let hp = HazardPointer::new(&mut thread);
+    assert!(!domain.is_quiescent());
+    assert!(!domain.quiesce(Duration::from_millis(10)));
+    drop(hp);
+    assert!(domain.is_quiescent());
+    assert!(domain.quiesce(Duration::ZERO));
+
+    scope(|s| {
+        s.spawn(|| {
+            let mut thread = Thread::new(&domain);
+            let hp = HazardPointer::new(&mut thread);
+            sleep(Duration::from_millis(10));
+            drop(hp);
+        });
+        assert!(domain.quiesce(Duration::from_secs(10)));
+    });
+}
+
+#[test]
+fn protected_ref_into_owned() {
+    let domain = Domain::new();
+    let mut thread = Thread::new(&domain);
+    let src = AtomicPtr::new(Box::into_raw(Box::new(42usize)));
+
+    let mut hp = HazardPointer::new(&mut thread);
+    let protected = unsafe { hp.protect_boxed(&src) }.unwrap();
+    assert_eq!(*protected, 42);
+    assert_eq!(protected.try_into_owned(&mut thread).as_deref(), Some(&42));
+    assert!(src.load(Relaxed).is_null());
+    assert!(unsafe { hp.protect_boxed(&src) }.is_none());
+
+    // Another protection of the same object prevents taking the ownership.
+    let ptr = Box::into_raw(Box::new(42usize));
+    src.store(ptr, Relaxed);
+    let mut other = HazardPointer::new(&mut thread);
+    other.protect_raw(ptr);
+    let protected = unsafe { hp.protect_boxed(&src) }.unwrap();
+    assert!(protected.try_into_owned(&mut thread).is_none());
+    assert!(src.load(Relaxed).is_null());
+    drop(other);
+    drop(hp);
+}
+
+#[test]
+#[should_panic(expected = "must belong to the domain of the hazard pointer")]
+fn protected_ref_into_owned_other_domain() {
+    let domain = Domain::new();
+    let other_domain = Domain::new();
+    let mut thread = Thread::new(&domain);
+    let mut other_thread = Thread::new(&other_domain);
+    let src = AtomicPtr::new(Box::into_raw(Box::new(42usize)));
+
+    let mut hp = HazardPointer::new(&mut thread);
+    let protected = unsafe { hp.protect_boxed(&src) }.unwrap();
+    let _ = protected.try_into_owned(&mut other_thread);
+}
+
+#[test]
+fn record_allocator() {
+    use core::alloc::Layout;
+    use core::sync::atomic::AtomicUsize;
+
+    struct CountingAllocator {
+        allocated: AtomicUsize,
+        deallocated: AtomicUsize,
+    }
+
+    unsafe impl RecordAllocator for CountingAllocator {
+        fn allocate(&self, layout: Layout) -> *mut u8 {
+            self.allocated.fetch_add(1, Relaxed);
+            GlobalRecordAllocator.allocate(layout)
+        }
+
+        unsafe fn deallocate(&self, ptr: *mut u8, layout: Layout) {
+            self.deallocated.fetch_add(1, Relaxed);
+            GlobalRecordAllocator.deallocate(ptr, layout)
+        }
+    }
+
+    static ALLOCATOR: CountingAllocator = CountingAllocator {
+        allocated: AtomicUsize::new(0),
+        deallocated: AtomicUsize::new(0),
+    };
+
+    let domain = Domain::with_allocator(&ALLOCATOR);
+    {
+        let mut thread = Thread::new(&domain);
+        // A thread record, its hazard array, and the index segment of the record.
+        assert_eq!(ALLOCATOR.allocated.load(Relaxed), 3);
+
+        // Grow the hazard array.
+        let hps: Vec<_> = (0..100).map(|_| HazardPointer::new(&mut thread)).collect();
+        assert_eq!(ALLOCATOR.allocated.load(Relaxed), 4);
+        drop(hps);
+    }
+    drop(domain);
+    // The old hazard array is retired and freed, and so are the record, its current hazard array
+    // and the index segment.
+    assert_eq!(ALLOCATOR.deallocated.load(Relaxed), 4);
+}
+
+#[test]
+fn free_filter() {
+    let drops = Drops::new();
+    let pinned = drops.node(0);
+    let pinned_addr = pinned as usize;
+    let mut domain = Domain::new();
+    domain.set_free_filter(move |ptr| ptr as usize != pinned_addr);
+    {
+        let mut thread = Thread::new(&domain);
+        unsafe { thread.retire(pinned) };
+        // Trigger a collection.
+        for i in 1..128 {
+            unsafe { thread.retire(drops.node(i)) };
+        }
+        assert_eq!(drops.count(), 127);
+        assert_eq!(domain.num_garbages(), 1);
+    }
+    drop(domain);
+    assert_eq!(drops.count(), 128);
+}
+
+#[test]
+fn free_filter_internal() {
+    use std::sync::atomic::AtomicBool;
+
+    static RAN: AtomicBool = AtomicBool::new(false);
+
+    let mut domain = Domain::new();
+    domain.set_free_filter(|_| false);
+    let mut thread = Thread::new(&domain);
+    // Grow the hazard array, retiring the old one.
+    let hps: Vec<_> = (0..100).map(|_| HazardPointer::new(&mut thread)).collect();
+    drop(hps);
+    thread.defer(|| RAN.store(true, Relaxed));
+    // The filter keeps neither the old array nor the deferred call, which needs two epochs.
+    for _ in 0..2 {
+        thread.force_reclaim();
+    }
+    assert!(RAN.load(Relaxed));
+    assert_eq!(domain.num_garbages(), 0);
+}
+
+#[test]
+fn node_cache() {
+    let domain = Domain::new();
+    let mut thread = Thread::new(&domain);
+    thread.register_node_cache::<[usize; 4]>(16);
+
+    let mut retired = Vec::new();
+    // Trigger a collection.
+    for _ in 0..128 {
+        let node = Box::into_raw(Box::new([0usize; 4]));
+        retired.push(node as usize);
+        unsafe { thread.retire(node) };
+    }
+    let nodes: Vec<_> = (0..16)
+        .map(|_| Box::into_raw(thread.alloc_node::<[usize; 4]>()))
+        .collect();
+    for &node in &nodes {
+        assert!(retired.contains(&(node as usize)));
+        drop(unsafe { Box::from_raw(node) });
+    }
+}
+
+#[test]
+fn node_cache_by_layout() {
+    let drops = Drops::new();
+    let domain = Domain::new();
+    let mut thread = Thread::new(&domain);
+    // `DropNode` has the layout of two words.
+    thread.register_node_cache::<[usize; 2]>(16);
+
+    let mut retired = Vec::new();
+    // Trigger a collection.
+    for i in 0..128 {
+        let node = drops.node(i);
+        retired.push(node as usize);
+        unsafe { thread.retire(node) };
+    }
+    // The cached nodes are dropped as what they were retired as.
+    assert_eq!(drops.count(), 128);
+    for _ in 0..16 {
+        let node = Box::into_raw(thread.alloc_node::<[usize; 2]>());
+        assert!(retired.contains(&(node as usize)));
+        drop(unsafe { Box::from_raw(node) });
+    }
+}
+
+#[test]
+fn merge_into() {
+    let drops = Drops::new();
+    let successor = Domain::new();
+    let domain = Domain::new();
+    {
+        let mut thread = Thread::new(&domain);
+        for i in 0..10 {
+            unsafe { thread.retire(drops.node(i)) };
+        }
+    }
+    assert_eq!(domain.num_garbages(), 10);
+    domain.merge_into(&successor);
+    assert_eq!(drops.count(), 0);
+    assert_eq!(successor.num_garbages(), 10);
+    drop(successor);
+    assert_eq!(drops.count(), 10);
+}
+
+#[test]
+fn merge_into_deferred() {
+    use core::sync::atomic::AtomicBool;
+
+    static RAN: AtomicBool = AtomicBool::new(false);
+
+    // Each retirement reclaims, which advances the epoch of the domain past that of `successor`.
+    let domain = Domain::with_config(DomainConfig {
+        flush_interval: 1,
+        collect_interval: 1,
+        ..DomainConfig::new()
+    });
+    let successor = Domain::new();
+    {
+        let mut thread = Thread::new(&domain);
+        for i in 0..10 {
+            unsafe { thread.retire(Box::into_raw(Box::new(i))) };
+        }
+        thread.defer(|| RAN.store(true, Relaxed));
+    }
+    domain.merge_into(&successor);
+
+    // The grace period restarts in the epoch of `successor`.
+    let mut thread = Thread::new(&successor);
+    thread.force_reclaim();
+    assert!(!RAN.load(Relaxed));
+    thread.force_reclaim();
+    assert!(RAN.load(Relaxed));
+}
+
+#[test]
+fn dry_run() {
+    let domain = Domain::new();
+    domain.set_dry_run(true);
+    {
+        let mut thread = Thread::new(&domain);
+        // Trigger a collection.
+        for i in 0..128usize {
+            unsafe { thread.retire(Box::into_raw(Box::new(i))) };
+        }
+    }
+    assert_eq!(domain.dry_run_freeable(), 128);
+    assert_eq!(domain.num_garbages(), 128);
+}
+
+#[test]
+fn protect_null() {
+    let domain = Domain::new();
+    let mut thread = Thread::new(&domain);
+    let mut hp = HazardPointer::new(&mut thread);
+    hp.protect_raw(core::ptr::null_mut::<usize>());
+    assert!(hp
+        .try_protect(core::ptr::null_mut(), &AtomicPtr::<usize>::default())
+        .is_ok());
+
+    // Trigger a collection, which frees everything as null guards nothing.
+    for i in 0..128usize {
+        unsafe { thread.retire(Box::into_raw(Box::new(i))) };
+    }
+    assert_eq!(domain.num_garbages(), 0);
+    drop(hp);
+}
+
+#[test]
+fn fifo_reclamation() {
+    let drops = Drops::new();
+    let domain = Domain::with_config(DomainConfig {
+        fifo_reclamation: true,
+        ..DomainConfig::new()
+    });
+    let mut thread = Thread::new(&domain);
+    // Trigger a collection of two flushed batches.
+    for i in 0..128 {
+        unsafe { thread.retire(drops.node(i)) };
+    }
+    assert_eq!(drops.ids(), (0..128).collect::<Vec<_>>());
+}
+
+#[test]
+fn max_hazard_bytes() {
+    let domain = Domain::with_config(DomainConfig {
+        max_hazard_bytes: 1024,
+        ..DomainConfig::new()
+    });
+    let mut thread = Thread::new(&domain);
+    let initial = domain.hazard_bytes();
+    assert!(initial > 0 && initial <= 1024);
+
+    // The initial array has 64 slots, and doubling it exceeds the cap.
+    let mut hps: Vec<_> = (0..64)
+        .map(|_| HazardPointer::try_new(&mut thread).unwrap())
+        .collect();
+    assert!(HazardPointer::try_new(&mut thread).is_none());
+    assert_eq!(domain.hazard_bytes(), initial);
+
+    hps.push(HazardPointer::new(&mut thread));
+    assert!(domain.hazard_bytes() > 1024);
+    drop(hps);
+}
+
+#[test]
+fn reclaim_abandoned() {
+    use core::sync::atomic::AtomicBool;
+
+    struct Node(AtomicBool, #[allow(dead_code)] DropNode);
+
+    impl Invalidate for Node {
+        fn invalidate(&self) {
+            self.0.store(true, Relaxed);
+        }
+
+        fn is_invalidated(&self) -> bool {
+            self.0.load(Relaxed)
+        }
+    }
+
+    impl Drop for Node {
+        fn drop(&mut self) {
+            assert!(self.0.load(Relaxed));
+        }
+    }
+
+    struct UnlinkNode(*mut Node);
+
+    impl Unlink<Node> for UnlinkNode {
+        fn do_unlink(&self) -> Result<Vec<*mut Node>, ()> {
+            Ok(vec![self.0])
+        }
+    }
+
+    let drops = Drops::new();
+    let node = |id| {
+        Box::into_raw(Box::new(Node(
+            AtomicBool::new(false),
+            DropNode { id, drops },
+        )))
+    };
+    let domain = Domain::new();
+    let frontier = node(0) as usize;
+    let result = scope(|s| {
+        s.spawn(|| {
+            let mut thread = Thread::new(&domain);
+            for i in 1..4 {
+                let node = node(i);
+                unsafe { thread.try_unlink(UnlinkNode(node), &[frontier as *mut Node]) };
+            }
+            panic!("worker panicked before invalidating");
+        })
+        .join()
+    });
+    assert!(result.is_err());
+    // The frontier stays protected until the abandoned pointers are invalidated.
+    assert!(!domain.is_quiescent());
+
+    let mut thread = Thread::new(&domain);
+    assert_eq!(domain.reclaim_abandoned(&mut thread), 3);
+    assert_eq!(domain.reclaim_abandoned(&mut thread), 0);
+    assert!(domain.is_quiescent());
+    drop(thread);
+    drop(domain);
+    assert_eq!(drops.ids(), [1, 2, 3]);
+
+    let frontier = unsafe { Box::from_raw(frontier as *mut Node) };
+    frontier.invalidate();
+}
+
+#[test]
+fn epoch_read_spins() {
+    use core::sync::atomic::AtomicBool;
+
+    struct Node(AtomicBool);
+
+    impl Invalidate for Node {
+        fn invalidate(&self) {
+            self.0.store(true, Relaxed);
+        }
+
+        fn is_invalidated(&self) -> bool {
+            self.0.load(Relaxed)
+        }
+    }
+
+    struct UnlinkNode(*mut Node);
+
+    impl Unlink<Node> for UnlinkNode {
+        fn do_unlink(&self) -> Result<Vec<*mut Node>, ()> {
+            Ok(vec![self.0])
+        }
+    }
+
+    // Every unlink invalidates and reclaims, so the epoch keeps advancing while it is read.
+    let domain = Domain::with_config(DomainConfig {
+        invalidation_interval: 1,
+        flush_interval: 1,
+        collect_interval: 1,
+        epoch_read_spins: 0,
+        ..DomainConfig::new()
+    });
+    scope(|s| {
+        for _ in 0..4 {
+            s.spawn(|| {
+                let mut thread = Thread::new(&domain);
+                for _ in 0..1024 {
+                    let node = Box::into_raw(Box::new(Node(AtomicBool::new(false))));
+                    assert!(unsafe { thread.try_unlink(UnlinkNode(node), &[]) });
+                }
+            });
+        }
+    });
+}
+
+#[test]
+fn reclaim_on_detach() {
+    let drops = Drops::new();
+    let domain = Domain::with_config(DomainConfig {
+        reclaim_on_detach: true,
+        ..DomainConfig::new()
+    });
+    let mut thread1 = Thread::new(&domain);
+    let thread2 = Thread::new(&domain);
+    assert_eq!(domain.attached_threads(), 2);
+    for i in 0..16 {
+        unsafe { thread1.retire(drops.node(i)) };
+    }
+    drop(thread1);
+    assert_eq!(domain.num_garbages(), 16);
+    assert_eq!(drops.count(), 0);
+
+    drop(thread2);
+    assert_eq!(domain.attached_threads(), 0);
+    assert_eq!(domain.num_garbages(), 0);
+    assert_eq!(drops.count(), 16);
+    drop(domain);
+    assert_eq!(drops.count(), 16);
+}
+
+#[test]
+fn reclaim_on_detach_grace() {
+    let drops = Drops::new();
+    let domain = Domain::with_config(DomainConfig {
+        reclaim_on_detach: true,
+        two_epoch_grace: true,
+        ..DomainConfig::new()
+    });
+    let mut thread = Thread::new(&domain);
+    for i in 0..16 {
+        unsafe { thread.retire(drops.node(i)) };
+    }
+    let deferred = drops.node(16) as usize;
+    thread.defer(move || drop(unsafe { Box::from_raw(deferred as *mut DropNode) }));
+    drop(thread);
+    assert_eq!(domain.num_garbages(), 0);
+    assert_eq!(drops.count(), 17);
+}
+
+#[test]
+fn with_capacity() {
+    let domain = Domain::with_capacity(4);
+    let reserved = domain.hazard_bytes();
+    assert_eq!(reserved, 4 * Domain::with_capacity(1).hazard_bytes());
+
+    // The threads take the reserved records instead of allocating new ones.
+    let threads: Vec<_> = (0..4).map(|_| Thread::new(&domain)).collect();
+    assert_eq!(domain.hazard_bytes(), reserved);
+    let thread = Thread::new(&domain);
+    assert!(domain.hazard_bytes() > reserved);
+    drop(thread);
+    drop(threads);
+}
+
+#[test]
+fn retire_raw_alloc() {
+    use core::alloc::Layout;
+    use std::alloc::alloc;
+
+    let layout = Layout::from_size_align(48, 16).unwrap();
+    let domain = Domain::with_config(DomainConfig {
+        flush_interval: 1,
+        collect_interval: 1,
+        ..DomainConfig::new()
+    });
+    let mut thread = Thread::new(&domain);
+    for _ in 0..16 {
+        let ptr = unsafe { alloc(layout) };
+        assert!(!ptr.is_null());
+        unsafe { thread.retire_raw_alloc(ptr, layout) };
+        assert_eq!(domain.num_garbages(), 0);
+    }
+}
+
+#[test]
+fn max_local_retired() {
+    let domain = Domain::new();
+    let mut thread = Thread::new(&domain);
+    thread.set_max_local_retired(4);
+    for i in 1..=16 {
+        unsafe { thread.retire(Box::into_raw(Box::new(i))) };
+        assert_eq!(thread.local_retired_len(), i % 4);
+        assert_eq!(domain.num_garbages(), i - i % 4);
+    }
+}
+
+#[test]
+fn mixed_deleters() {
+    use core::alloc::Layout;
+    use std::alloc::alloc;
+
+    let drops = [Drops::new(), Drops::new()];
+    let layout = Layout::new::<[u64; 4]>();
+    let domain = Domain::new();
+    let mut thread = Thread::new(&domain);
+    // Interleave the deleters within the collection after 128 retirements.
+    for i in 0..43 {
+        unsafe {
+            thread.retire(drops[0].node(i));
+            // Another type, with another deleter.
+            let node = DropNode {
+                id: i,
+                drops: drops[1],
+            };
+            thread.retire(Box::into_raw(Box::new((node, i as u8))));
+            thread.retire_raw_alloc(alloc(layout), layout);
+        }
+    }
+    assert_eq!(domain.num_garbages(), 0);
+    assert_eq!(thread.local_retired_len(), 1);
+    assert_eq!(drops[0].count(), 43);
+    assert_eq!(drops[1].count(), 43);
+}
+
+#[test]
+fn last_guarded_count() {
+    let domain = Domain::new();
+    let mut thread = Thread::new(&domain);
+    let mut reader = Thread::new(&domain);
+    assert_eq!(domain.last_guarded_count(), 0);
+
+    let ptrs: Vec<_> = (0..8).map(|i| Box::into_raw(Box::new(i))).collect();
+    let mut hps: Vec<_> = (0..8).map(|_| HazardPointer::new(&mut reader)).collect();
+    for (hp, &ptr) in hps.iter_mut().zip(&ptrs) {
+        hp.protect_raw(ptr);
+    }
+    // Trigger a collection.
+    for &ptr in &ptrs {
+        unsafe { thread.retire(ptr) };
+    }
+    for i in 8..128 {
+        unsafe { thread.retire(Box::into_raw(Box::new(i))) };
+    }
+    // The hazard pointer protecting the hazard arrays while scanning them is counted as well.
+    assert!(domain.last_guarded_count() >= 8);
+    assert_eq!(domain.num_garbages(), 8);
+    drop(hps);
+}
+
+#[test]
+fn grow_array_at_collection() {
+    let drops = Drops::new();
+    // Retiring the old hazard array collects right away.
+    let domain = Domain::with_config(DomainConfig {
+        flush_interval: 1,
+        collect_interval: 1,
+        ..DomainConfig::new()
+    });
+    let mut thread = Thread::new(&domain);
+    let mut reclaimer = Thread::new(&domain);
+    let nodes: Vec<_> = (0..200).map(|i| drops.node(i)).collect();
+    let mut hps = Vec::new();
+    for &node in &nodes {
+        let mut hp = HazardPointer::new(&mut thread);
+        hp.protect_raw(node);
+        hps.push(hp);
+    }
+
+    // The protections survive the growths.
+    for &node in &nodes {
+        unsafe { reclaimer.retire(node) };
+    }
+    assert_eq!(drops.count(), 0);
+    drop(hps);
+    unsafe { reclaimer.retire(drops.node(200)) };
+    assert_eq!(drops.count(), 201);
+}
+
+#[test]
+fn full_array_at_collection() {
+    let domain = Domain::new();
+    let mut thread = Thread::new(&domain);
+    let initial = domain.hazard_bytes();
+    let mut hps: Vec<_> = (0..64).map(|_| HazardPointer::new(&mut thread)).collect();
+    assert_eq!(domain.hazard_bytes(), initial);
+
+    // The scan doesn't take a hazard pointer of the reclaimer, which would grow its array.
+    let node = Box::into_raw(Box::new(0));
+    hps[0].protect_raw(node);
+    unsafe { thread.retire(node) };
+    assert_eq!(thread.force_reclaim(), 0);
+    assert_eq!(domain.hazard_bytes(), initial);
+    drop(hps);
+    assert_eq!(thread.force_reclaim(), 1);
+}
+
+#[test]
+fn replace() {
+    const THREADS: usize = 4;
+    const ITER: usize = 1024;
+
+    let domain = Domain::new();
+    let src = AtomicPtr::new(Box::into_raw(Box::new(0usize)));
+    scope(|s| {
+        for _ in 0..THREADS {
+            s.spawn(|| {
+                let mut thread = Thread::new(&domain);
+                for _ in 0..ITER {
+                    while !unsafe { thread.replace(&src, |old| Box::into_raw(Box::new(*old + 1))) }
+                    {
+                    }
+                }
+            });
+        }
+    });
+    let last = unsafe { Box::from_raw(src.load(Relaxed)) };
+    assert_eq!(*last, THREADS * ITER);
+}
+
+#[test]
+fn suspend_collection() {
+    let domain = Domain::new();
+    let mut thread = Thread::new(&domain);
+    {
+        let mut guard = thread.suspend_collection();
+        for i in 0..256 {
+            unsafe { guard.retire(Box::into_raw(Box::new(i))) };
+        }
+        // Flushed, but not collected.
+        assert_eq!(domain.num_garbages(), 256);
+    }
+    // The skipped collection runs when the guard is dropped.
+    assert_eq!(domain.num_garbages(), 0);
+
+    for i in 0..128 {
+        unsafe { thread.retire(Box::into_raw(Box::new(i))) };
+    }
+    assert_eq!(domain.num_garbages(), 0);
+}
+
+#[test]
+fn protect_for_cas() {
+    let domain = Domain::new();
+    let mut thread = Thread::new(&domain);
+    let mut hp = HazardPointer::new(&mut thread);
+    let old = Box::into_raw(Box::new(1));
+    let src = AtomicPtr::new(old);
+    assert!(hp.protect_for_cas(&src, old));
+
+    let new = Box::into_raw(Box::new(2));
+    assert_eq!(src.compare_exchange(old, new, AcqRel, Relaxed), Ok(old));
+    assert!(!hp.protect_for_cas(&src, old));
+    drop(hp);
+    drop(thread);
+    unsafe {
+        drop(Box::from_raw(old));
+        drop(Box::from_raw(src.into_inner()));
+    }
+}
+
+#[test]
+fn thread_local_thread() {
+    use core::cell::Cell;
+
+    static DOMAIN: Domain = Domain::new();
+
+    thread_local_thread!(static THREAD = &DOMAIN;);
+
+    struct RetireOnDrop(Cell<*mut usize>);
+
+    impl Drop for RetireOnDrop {
+        fn drop(&mut self) {
+            // The `Thread` of `THREAD` may be destroyed already.
+            THREAD.with(|thread| unsafe { thread.retire(self.0.get()) });
+        }
+    }
+
+    thread_local! {
+        static RETIRE_ON_DROP: RetireOnDrop = const { RetireOnDrop(Cell::new(core::ptr::null_mut())) };
+    }
+
+    std::thread::spawn(|| {
+        RETIRE_ON_DROP.with(|r| r.0.set(Box::into_raw(Box::new(1))));
+        THREAD.with(|thread| {
+            unsafe { thread.retire(Box::into_raw(Box::new(2))) };
+            // Nested uses get a temporary `Thread`.
+            THREAD.with(|thread| unsafe { thread.retire(Box::into_raw(Box::new(3))) });
+        });
+    })
+    .join()
+    .unwrap();
+    assert_eq!(DOMAIN.num_garbages(), 3);
+}
+
+#[test]
+fn local_thread_retire() {
+    static DOMAIN: Domain = Domain::new();
+
+    thread_local_thread!(static THREAD = &DOMAIN;);
+
+    std::thread::spawn(|| {
+        THREAD.with(|_| {
+            // The `Thread` is in use, so it retires to the domain without a temporary `Thread`.
+            let hazard_bytes = DOMAIN.hazard_bytes();
+            unsafe { THREAD.retire(Box::into_raw(Box::new(1))) };
+            assert_eq!(DOMAIN.hazard_bytes(), hazard_bytes);
+            assert_eq!(DOMAIN.num_garbages(), 1);
+        });
+        unsafe { THREAD.retire(Box::into_raw(Box::new(2))) };
+    })
+    .join()
+    .unwrap();
+    assert_eq!(DOMAIN.num_garbages(), 2);
+}
+
+#[test]
+fn two_epoch_grace() {
+    let drops = Drops::new();
+    // Each retirement flushes and reclaims, which advances the epoch once.
+    let domain = Domain::with_config(DomainConfig {
+        flush_interval: 1,
+        collect_interval: 1,
+        two_epoch_grace: true,
+        ..DomainConfig::new()
+    });
+    let mut thread = Thread::new(&domain);
+    for i in 0..16 {
+        unsafe { thread.retire(drops.node(i)) };
+        // Only the last one is kept, although none of them is guarded.
+        assert_eq!(domain.num_garbages(), 1);
+        assert_eq!(drops.count(), i);
+    }
+}
+
+#[test]
+fn protect_token() {
+    let domain = Domain::new();
+    let mut thread = Thread::new(&domain);
+    let mut hp = HazardPointer::new(&mut thread);
+    let mut other = HazardPointer::new(&mut thread);
+    let ptr = Box::into_raw(Box::new(1));
+    let src = AtomicPtr::new(ptr);
+
+    let token = hp.protect_token(src.load(Relaxed));
+    assert_eq!(token.as_ptr(), ptr);
+    assert!(hp.validate_token(token, &src));
+    assert!(!other.validate_token(token, &src));
+
+    // The slot protects something else.
+    other.protect_raw(ptr);
+    hp.reset_protection();
+    assert!(!hp.validate_token(token, &src));
+
+    // `src` has changed.
+    let token = hp.protect_token(ptr);
+    src.store(core::ptr::null_mut(), Relaxed);
+    assert!(!hp.validate_token(token, &src));
+
+    drop((hp, other));
+    drop(unsafe { Box::from_raw(ptr) });
+}
+
+#[test]
+fn recount_garbage() {
+    let domain = Domain::new();
+    let mut thread = Thread::new(&domain);
+    for i in 0..100 {
+        unsafe { thread.retire(Box::into_raw(Box::new(i))) };
+    }
+    assert_eq!(domain.num_garbages(), 64);
+    assert_eq!(domain.recount_garbage(&mut thread), 100);
+    assert_eq!(domain.num_garbages(), 100);
+    assert_eq!(thread.local_retired_len(), 0);
+}
+
+#[test]
+fn thread_churn() {
+    const THREADS: usize = 8;
+    const ITER: usize = 1024;
+
+    let domain = Domain::new();
+    scope(|s| {
+        for _ in 0..THREADS {
+            s.spawn(|| {
+                for i in 0..ITER {
+                    let mut thread = Thread::new(&domain);
+                    unsafe { thread.retire(Box::into_raw(Box::new(i))) };
+                }
+            });
+        }
+    });
+    // The records are recycled, so there are at most as many as concurrent threads.
+    assert!(domain.hazard_bytes() <= THREADS * Domain::with_capacity(1).hazard_bytes());
+}
+
+#[test]
+fn apply_config() {
+    let domain = Domain::new();
+    let mut thread = Thread::new(&domain);
+    let snapshot = domain.config_snapshot();
+
+    domain.apply_config(DomainConfig {
+        flush_interval: 1,
+        ..snapshot
+    });
+    assert_eq!(domain.config().flush_interval, 1);
+    unsafe { thread.retire(Box::into_raw(Box::new(0))) };
+    assert_eq!(thread.flush_interval(), 1);
+    assert_eq!(domain.num_garbages(), 1);
+
+    domain.apply_config(snapshot);
+    assert_eq!(domain.config_snapshot(), snapshot);
+    unsafe { thread.retire(Box::into_raw(Box::new(1))) };
+    assert_eq!(thread.flush_interval(), 64);
+    assert_eq!(thread.local_retired_len(), 1);
+}
+
+#[cfg(feature = "chrome-trace")]
+#[test]
+fn chrome_trace() {
+    use std::sync::Arc;
+
+    #[derive(Clone, Default)]
+    struct SharedBuf(Arc<Mutex<Vec<u8>>>);
+
+    impl std::io::Write for SharedBuf {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    let domain = Domain::new();
+    let buf = SharedBuf::default();
+    domain.set_trace_writer(buf.clone());
+    let mut thread = Thread::new(&domain);
+    for i in 0..128 {
+        unsafe { thread.retire(Box::into_raw(Box::new(i))) };
+    }
+    domain.stop_trace();
+
+    let trace = String::from_utf8(buf.0.lock().unwrap().clone()).unwrap();
+    assert!(trace.starts_with("[\n") && trace.ends_with("\n]\n"));
+    let events: Vec<_> = trace.lines().filter(|l| l.starts_with('{')).collect();
+    assert_eq!(events.len(), 4);
+    assert!(events[0].contains(r#""name":"flush""#) && events[0].contains(r#""retired":64"#));
+    assert!(events[2].contains(r#""name":"barrier","cat":"hp_pp","ph":"X""#));
+    assert!(events[3].contains(r#""name":"reclaim""#) && events[3].contains(r#""freed":128"#));
+}
+
+#[test]
+fn spawn_collector() {
+    static DOMAIN: Domain = Domain::with_config(DomainConfig {
+        flush_interval: 8,
+        collect_interval: usize::MAX,
+        ..DomainConfig::new()
+    });
+
+    let collector = DOMAIN.spawn_collector(Duration::from_millis(1), 16);
+    scope(|s| {
+        for _ in 0..4 {
+            s.spawn(|| {
+                let mut thread = Thread::new(&DOMAIN);
+                for i in 0..1024 {
+                    unsafe { thread.retire(Box::into_raw(Box::new(i))) };
+                }
+            });
+        }
+    });
+    // The workers never collect, and their last flushes are reclaimed on shutdown.
+    collector.shutdown();
+    assert_eq!(DOMAIN.num_garbages(), 0);
+}
+
+#[test]
+fn retire_hot() {
+    let drops = Drops::new();
+    let domain = Domain::with_config(DomainConfig {
+        fifo_reclamation: true,
+        ..DomainConfig::new()
+    });
+    let mut thread = Thread::new(&domain);
+    for i in 0..3 {
+        unsafe { thread.retire(drops.node(i)) };
+    }
+    // A hot retirement reclaims right away, except the guarded hot node.
+    let hot = drops.node(100);
+    let mut hp = HazardPointer::new(&mut thread);
+    hp.protect_raw(hot);
+    unsafe { thread.retire_hot(hot) };
+    assert_eq!(drops.ids(), [0, 1, 2]);
+
+    // Hot nodes are freed first.
+    drop(hp);
+    for i in 3..6 {
+        unsafe { thread.retire(drops.node(i)) };
+    }
+    unsafe { thread.retire_hot(drops.node(101)) };
+    assert_eq!(drops.ids(), [0, 1, 2, 100, 101, 3, 4, 5]);
+}
+
+#[test]
+fn with_scratch_domain() {
+    let drops = Drops::new();
+    let domain = Domain::new();
+    let mut thread = Thread::new(&domain);
+    let garbages = thread.with_scratch_domain(|scratch| {
+        for i in 0..100 {
+            unsafe { scratch.retire(drops.node(i)) };
+        }
+        scratch.local_retired_len()
+    });
+    assert_eq!(garbages, 100 - 64);
+    assert_eq!(drops.count(), 100);
+    assert_eq!(domain.num_garbages(), 0);
+    assert_eq!(thread.local_retired_len(), 0);
+}
+
+#[test]
+fn defer() {
+    use std::sync::atomic::AtomicBool;
+
+    static RAN: AtomicBool = AtomicBool::new(false);
+
+    let domain = Domain::with_config(DomainConfig {
+        flush_interval: 8,
+        collect_interval: 8,
+        ..DomainConfig::new()
+    });
+    let collect_interval = domain.config().collect_interval;
+    let mut thread = Thread::new(&domain);
+    thread.defer(|| RAN.store(true, Relaxed));
+    // Each reclamation advances the epoch once.
+    for i in 0..collect_interval {
+        unsafe { thread.retire(Box::into_raw(Box::new(i))) };
+    }
+    assert!(!RAN.load(Relaxed));
+    for i in 0..collect_interval {
+        unsafe { thread.retire(Box::into_raw(Box::new(i))) };
+    }
+    assert!(RAN.load(Relaxed));
+}
+
+#[test]
+fn retire_rate_limit() {
+    let domain = Domain::new();
+    let mut thread = Thread::new(&domain);
+    thread.set_retire_rate_limit(Some(100));
+    assert_eq!(thread.retire_rate_limit(), Some(100));
+
+    // A burst beyond the bucket defers the frees of its reclamation.
+    for i in 0..128 {
+        unsafe { thread.retire(Box::into_raw(Box::new(i))) };
+    }
+    assert_eq!(thread.pending_frees(), 128);
+    assert_eq!(domain.num_garbages(), 0);
+    for i in 0..10 {
+        unsafe { thread.retire(Box::into_raw(Box::new(i))) };
+    }
+    assert_eq!(thread.pending_frees(), 128 - 10 * 2);
+
+    thread.set_retire_rate_limit(None);
+    assert_eq!(thread.retire_rate_limit(), None);
+    drop(thread);
+}
+
+#[test]
+fn pending_frees_while_panicking() {
+    use core::sync::atomic::AtomicBool;
+
+    static DROPPED_WHILE_PANICKING: AtomicBool = AtomicBool::new(false);
+
+    struct Node(#[allow(dead_code)] DropNode);
+
+    impl Drop for Node {
+        fn drop(&mut self) {
+            if std::thread::panicking() {
+                DROPPED_WHILE_PANICKING.store(true, Relaxed);
+            }
+        }
+    }
+
+    let drops = Drops::new();
+    let domain = Domain::with_config(DomainConfig {
+        flush_interval: 8,
+        collect_interval: 8,
+        ..DomainConfig::new()
+    });
+    let result = scope(|s| {
+        s.spawn(|| {
+            let mut thread = Thread::new(&domain);
+            thread.set_retire_rate_limit(Some(1));
+            for id in 0..8 {
+                let node = Box::into_raw(Box::new(Node(DropNode { id, drops })));
+                unsafe { thread.retire(node) };
+            }
+            assert_eq!(thread.pending_frees(), 8);
+            panic!("worker panicked with pending frees");
+        })
+        .join()
+    });
+    assert!(result.is_err());
+    // The pending frees are left to the domain.
+    assert_eq!(drops.count(), 0);
+    assert_eq!(domain.num_garbages(), 8);
+
+    let mut thread = Thread::new(&domain);
+    assert_eq!(thread.force_reclaim(), 8);
+    assert_eq!(drops.count(), 8);
+    assert!(!DROPPED_WHILE_PANICKING.load(Relaxed));
+}
+
+#[test]
+fn aggressive_collect_interval() {
+    let domain = Domain::with_config(DomainConfig {
+        flush_interval: 16,
+        collect_interval: 16,
+        ..DomainConfig::new()
+    });
+    let mut thread = Thread::new(&domain);
+    for i in 0..1000 {
+        unsafe { thread.retire(Box::into_raw(Box::new(i))) };
+        assert!(domain.num_garbages() < 16);
+    }
+}
+
+#[test]
+fn max_garbage() {
+    let domain = Domain::with_config(DomainConfig {
+        collect_interval: usize::MAX,
+        max_garbage: 1024,
+        ..DomainConfig::new()
+    });
+    let mut thread = Thread::new(&domain);
+    let mut peak = 0;
+    for _ in 0..1_000_000 {
+        unsafe { thread.retire(Box::into_raw(Box::new(0u8))) };
+        peak = peak.max(domain.num_garbages());
+    }
+    // Exceeded by at most one flush.
+    assert!(peak <= 1024 + thread.flush_interval());
+    assert!(domain.stats().freed > 0);
+}
+
+#[test]
+#[should_panic(expected = "must not exceed `collect_interval`")]
+fn flush_interval_exceeding_collect_interval() {
+    let _ = Domain::with_config(DomainConfig {
+        collect_interval: 16,
+        ..DomainConfig::new()
+    });
+}
+
+#[test]
+fn protect_computed() {
+    let domain = Domain::with_config(DomainConfig {
+        flush_interval: 8,
+        collect_interval: 8,
+        ..DomainConfig::new()
+    });
+    let collect_interval = domain.config().collect_interval;
+    let mut thread = Thread::new(&domain);
+    let target = Box::into_raw(Box::new(42));
+    let inner = AtomicPtr::new(target);
+    let outer = AtomicPtr::new(&inner as *const _ as *mut AtomicPtr<i32>);
+
+    let mut hp = HazardPointer::new(&mut thread);
+    let ptr = hp.protect_computed(|| unsafe { (*outer.load(Acquire)).load(Acquire) });
+    assert_eq!(ptr, target);
+
+    // The protected target survives a reclamation.
+    inner.store(core::ptr::null_mut(), Release);
+    unsafe { thread.retire(target) };
+    for i in 1..collect_interval {
+        unsafe { thread.retire(Box::into_raw(Box::new(i))) };
+    }
+    assert_eq!(domain.num_garbages(), 1);
+    assert_eq!(unsafe { *ptr }, 42);
+    drop(hp);
+}
+
+#[test]
+fn force_reclaim() {
+    let domain = Domain::new();
+    let mut thread = Thread::new(&domain);
+    assert_eq!(thread.force_reclaim(), 0);
+
+    let guarded = Box::into_raw(Box::new(0));
+    let mut hp = HazardPointer::new(&mut thread);
+    hp.protect_raw(guarded);
+    unsafe { thread.retire(guarded) };
+    for i in 1..10 {
+        unsafe { thread.retire(Box::into_raw(Box::new(i))) };
+    }
+    assert_eq!(thread.force_reclaim(), 9);
+    assert_eq!(thread.local_retired_len(), 0);
+    assert_eq!(domain.num_garbages(), 1);
+
+    drop(hp);
+    assert_eq!(thread.force_reclaim(), 1);
+    assert_eq!(domain.num_garbages(), 0);
+}
+
+#[test]
+fn shrink_array() {
+    let domain = Domain::with_config(DomainConfig {
+        flush_interval: 8,
+        collect_interval: 8,
+        ..DomainConfig::new()
+    });
+    let collect_interval = domain.config().collect_interval;
+    let mut thread = Thread::new(&domain);
+    let initial = domain.hazard_bytes();
+
+    let hps: Vec<_> = (0..256).map(|_| HazardPointer::new(&mut thread)).collect();
+    assert!(domain.hazard_bytes() > initial);
+    drop(hps);
+
+    for i in 0..collect_interval {
+        unsafe { thread.retire(Box::into_raw(Box::new(i))) };
+    }
+    assert_eq!(domain.hazard_bytes(), initial);
+}
+
+#[test]
+fn current_epoch() {
+    let domain = Domain::with_config(DomainConfig {
+        flush_interval: 8,
+        collect_interval: 8,
+        ..DomainConfig::new()
+    });
+    let collect_interval = domain.config().collect_interval;
+    let mut thread = Thread::new(&domain);
+    let epoch = domain.current_epoch();
+    assert!(!domain.grace_period_elapsed(epoch));
+
+    // Each reclamation advances the epoch once.
+    for i in 0..collect_interval {
+        unsafe { thread.retire(Box::into_raw(Box::new(i))) };
+    }
+    assert_eq!(domain.current_epoch().advances_since(epoch), 1);
+    assert!(!domain.grace_period_elapsed(epoch));
+    for i in 0..collect_interval {
+        unsafe { thread.retire(Box::into_raw(Box::new(i))) };
+    }
+    assert!(domain.grace_period_elapsed(epoch));
+}
+
+#[test]
+fn retire_many() {
+    let domain = Domain::new();
+    let mut thread = Thread::new(&domain);
+    let batch = |n: usize| {
+        (0..n)
+            .map(|i| Box::into_raw(Box::new(i)))
+            .collect::<Vec<_>>()
+    };
+
+    // A batch crossing the cadence flushes and collects once.
+    unsafe { thread.retire_many(&batch(200)) };
+    assert_eq!(thread.local_retired_len(), 0);
+    assert_eq!(domain.num_garbages(), 0);
+
+    unsafe { thread.retire_many(&batch(10)) };
+    assert_eq!(thread.local_retired_len(), 10);
+    assert_eq!(domain.num_garbages(), 0);
+
+    unsafe { thread.retire_many(&batch(50)) };
+    assert_eq!(thread.local_retired_len(), 0);
+    assert_eq!(domain.num_garbages(), 0);
+}
+
+#[cfg(feature = "debug-checks")]
+#[test]
+fn long_lived_garbage() {
+    let domain = Domain::new();
+    let mut thread = Thread::new(&domain);
+    let leaked = Box::into_raw(Box::new(0));
+    let mut hp = HazardPointer::new(&mut thread);
+    hp.protect_raw(leaked);
+    unsafe { thread.retire(leaked) };
+    for _ in 0..3 {
+        for i in 0..128 {
+            unsafe { thread.retire(Box::into_raw(Box::new(i))) };
+        }
+    }
+    assert_eq!(domain.long_lived_garbage(2), [(leaked.cast(), 3)]);
+    assert!(domain.long_lived_garbage(3).is_empty());
+    assert_eq!(domain.num_garbages(), 1);
+    drop(hp);
+}
+
+#[test]
+fn stats() {
+    const FLUSH: usize = 4;
+    const COLLECT: usize = 16;
+    const RETIRES: usize = COLLECT + 7;
+
+    let domain = Domain::with_config(DomainConfig {
+        flush_interval: FLUSH,
+        collect_interval: COLLECT,
+        ..DomainConfig::new()
+    });
+    let mut thread = Thread::new(&domain);
+    assert_eq!(domain.stats().retired, 0);
+
+    let guarded = Box::into_raw(Box::new(0));
+    let mut hp = HazardPointer::new(&mut thread);
+    hp.protect_raw(guarded);
+    unsafe { thread.retire(guarded) };
+    for i in 1..RETIRES {
+        unsafe { thread.retire(Box::into_raw(Box::new(i))) };
+    }
+    // One collection, which keeps the guarded pointer, and the flushes since.
+    let stats = domain.stats();
+    let flushed = RETIRES / FLUSH * FLUSH;
+    assert_eq!(stats.retired, flushed);
+    assert_eq!(stats.freed, COLLECT - 1);
+    assert_eq!(stats.reclamation_barriers, 1);
+    assert_eq!(stats.num_garbages, flushed - (COLLECT - 1));
+    assert_eq!(stats.last_guarded_count, 1);
+    drop(hp);
+}
+
+/// A `HazardStore` of boxed slots, counting the scans of reclamations.
+#[derive(Debug)]
+struct BoxedSlots(Box<[AtomicPtr<u8>]>);
+
+static BOXED_SLOTS_SCANS: core::sync::atomic::AtomicUsize = core::sync::atomic::AtomicUsize::new(0);
+
+unsafe impl HazardStore for BoxedSlots {
+    fn alloc(len: usize, _: &'static dyn RecordAllocator) -> *mut Self {
+        let slots = (0..len).map(|_| AtomicPtr::new(core::ptr::null_mut()));
+        Box::into_raw(Box::new(BoxedSlots(slots.collect())))
+    }
+
+    unsafe fn dealloc(ptr: *mut u8) {
+        drop(Box::from_raw(ptr.cast::<Self>()));
+    }
+
+    fn size_of(len: usize) -> usize {
+        core::mem::size_of::<Self>() + len * core::mem::size_of::<AtomicPtr<u8>>()
+    }
+
+    fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    unsafe fn slot(&self, idx: usize) -> &AtomicPtr<u8> {
+        &self.0[idx]
+    }
+
+    fn iter_protected(&self) -> impl Iterator<Item = *mut u8> + '_ {
+        BOXED_SLOTS_SCANS.fetch_add(1, Relaxed);
+        self.0
+            .iter()
+            .map(|slot| slot.load(Acquire))
+            .filter(|ptr| !ptr.is_null())
+    }
+}
+
+#[test]
+fn hazard_store() {
+    let domain =
+        Domain::<BoxedSlots>::with_hazard_store(DomainConfig::new(), &GlobalRecordAllocator);
+    let mut thread = Thread::new(&domain);
+
+    // Grows the store, keeping the protections.
+    let hps: Vec<_> = (0..100).map(|_| HazardPointer::new(&mut thread)).collect();
+    let guarded = Box::into_raw(Box::new(0));
+    let mut hp = HazardPointer::new(&mut thread);
+    hp.protect_raw(guarded);
+    drop(hps);
+    // Frees the old store.
+    assert_eq!(thread.force_reclaim(), 1);
+
+    unsafe { thread.retire(guarded) };
+    let scans = BOXED_SLOTS_SCANS.load(Relaxed);
+    assert_eq!(thread.force_reclaim(), 0);
+    assert!(BOXED_SLOTS_SCANS.load(Relaxed) > scans);
+    assert_eq!(domain.num_garbages(), 1);
+
+    drop(hp);
+    assert_eq!(thread.force_reclaim(), 1);
+}
+
+#[test]
+fn hazard_store_capacity() {
+    let domain = Domain::<BoxedSlots>::with_hazard_store_capacity(
+        DomainConfig::new(),
+        &GlobalRecordAllocator,
+        2,
+    );
+    let reserved = domain.hazard_bytes();
+    assert!(reserved > 0);
+
+    // The threads take the reserved records instead of allocating new ones.
+    let threads: Vec<_> = (0..2).map(|_| Thread::new(&domain)).collect();
+    assert_eq!(domain.hazard_bytes(), reserved);
+    drop(threads);
+}
+
+#[test]
+fn retire_with() {
+    static DELETED: core::sync::atomic::AtomicUsize = core::sync::atomic::AtomicUsize::new(0);
+    unsafe fn delete(ptr: *mut u8) {
+        drop(Box::from_raw(ptr.cast::<[u8; 3]>()));
+        DELETED.fetch_add(1, Relaxed);
+    }
+
+    let domain = Domain::new();
+    let mut thread = Thread::new(&domain);
+    unsafe { thread.retire_with(Box::into_raw(Box::new([1u8, 2, 3])), delete) };
+    assert_eq!(DELETED.load(Relaxed), 0);
+    assert_eq!(thread.force_reclaim(), 1);
+    assert_eq!(DELETED.load(Relaxed), 1);
+
+    drop(thread);
+    drop(domain);
+    assert_eq!(DELETED.load(Relaxed), 1);
+}
+
+#[test]
+fn default_domain() {
+    let drops = Drops::new();
+    let src = AtomicPtr::new(drops.node(0));
+    let (hp, ptr) = protect(&src);
+    src.store(core::ptr::null_mut(), Release);
+    unsafe { retire(ptr) };
+    // Each retirement goes through the flush and collect cadence.
+    let retire_many = || {
+        for i in 0..1024 {
+            unsafe { retire(Box::into_raw(Box::new(i))) };
+        }
+    };
+    retire_many();
+    assert_eq!(drops.count(), 0);
+
+    drop(hp);
+    // Another test may be reclaiming the default domain concurrently.
+    for _ in 0..100 {
+        retire_many();
+        if drops.count() == 1 {
+            break;
+        }
+        sleep(Duration::from_millis(1));
+    }
+    assert_eq!(drops.count(), 1);
+}
+
+#[test]
+fn retire_global() {
+    let drops = Drops::new();
+    let domain = Domain::new();
+    scope(|s| {
+        s.spawn(|| unsafe { domain.retire_global(drops.node(0)) });
+    });
+    assert_eq!(domain.num_garbages(), 1);
+    assert_eq!(drops.count(), 0);
+
+    scope(|s| {
+        s.spawn(|| {
+            let mut thread = Thread::new(&domain);
+            assert_eq!(thread.force_reclaim(), 1);
+        });
+    });
+    assert_eq!(drops.count(), 1);
+    assert_eq!(domain.num_garbages(), 0);
+}
+
+#[test]
+fn try_unlink_panic() {
+    struct Node;
+    impl Invalidate for Node {
+        fn invalidate(&self) {}
+    }
+    struct PanickingUnlink;
+    impl Unlink<Node> for PanickingUnlink {
+        fn do_unlink(&self) -> Result<Vec<*mut Node>, ()> {
+            panic!("unlink failed");
+        }
+    }
+
+    let domain = Domain::new();
+    let mut thread = Thread::new(&domain);
+    let frontier = Box::into_raw(Box::new(Node));
+    let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| unsafe {
+        thread.try_unlink(PanickingUnlink, &[frontier])
+    }));
+    assert!(result.is_err());
+    // The protection of the frontier is released.
+    assert!(domain.is_quiescent());
+    drop(unsafe { Box::from_raw(frontier) });
+}
+
+#[test]
+fn guarded_ptrs() {
+    let domain = Domain::new();
+    let mut thread = Thread::new(&domain);
+    assert_eq!(thread.active_hazards(), 0);
+    assert!(thread.guarded_ptrs().is_empty());
+
+    let (a, b) = (Box::into_raw(Box::new(1)), Box::into_raw(Box::new(2)));
+    let mut hp_a = HazardPointer::new(&mut thread);
+    let mut hp_b = HazardPointer::new(&mut thread);
+    let hp_none = HazardPointer::new(&mut thread);
+    hp_a.protect_raw(a);
+    hp_b.protect_raw(b);
+    assert_eq!(thread.active_hazards(), 3);
+    let mut guarded = thread.guarded_ptrs();
+    guarded.sort();
+    let mut expected = vec![a.cast::<u8>(), b.cast()];
+    expected.sort();
+    assert_eq!(guarded, expected);
+
+    drop((hp_a, hp_b, hp_none));
+    assert_eq!(thread.active_hazards(), 0);
+    assert!(thread.guarded_ptrs().is_empty());
+    unsafe { drop((Box::from_raw(a), Box::from_raw(b))) };
+}
+
+#[cfg(feature = "debug-checks")]
+#[test]
+#[should_panic(expected = "is retired again before it is freed")]
+fn double_retire() {
+    let domain = Domain::new();
+    let mut thread = Thread::new(&domain);
+    let ptr = Box::into_raw(Box::new(0));
+    unsafe {
+        thread.retire(ptr);
+        thread.retire(ptr);
+    }
+}
+
+#[cfg(feature = "debug-checks")]
+#[test]
+fn retire_after_free() {
+    let domain = Domain::new();
+    let mut thread = Thread::new(&domain);
+    // The allocator may reuse the address of a freed pointer.
+    for _ in 0..1000 {
+        unsafe { thread.retire(Box::into_raw(Box::new(0))) };
+        thread.force_reclaim();
+    }
+}
+
+#[test]
+fn detach_and_adopt() {
+    let drops = Drops::new();
+    let old = Domain::new();
+    let new = Domain::new();
+    let mut thread = Thread::new(&old);
+    for i in 0..10 {
+        unsafe { thread.retire(drops.node(i)) };
+    }
+    let retireds = thread.detach();
+    assert_eq!(retireds.len(), 10);
+    assert_eq!(old.num_garbages(), 0);
+    assert_eq!(old.attached_threads(), 0);
+
+    let mut thread = unsafe { Thread::adopt(&new, retireds) };
+    assert_eq!(new.num_garbages(), 10);
+    assert_eq!(drops.count(), 0);
+    assert_eq!(thread.force_reclaim(), 10);
+    assert_eq!(drops.count(), 10);
+}
+
+#[test]
+fn detach_unlinked() {
+    struct Node(#[allow(dead_code)] DropNode);
+
+    impl Invalidate for Node {
+        fn invalidate(&self) {}
+    }
+
+    struct UnlinkNode(*mut Node);
+
+    impl Unlink<Node> for UnlinkNode {
+        fn do_unlink(&self) -> Result<Vec<*mut Node>, ()> {
+            Ok(vec![self.0])
+        }
+    }
+
+    let drops = Drops::new();
+    let old = Domain::new();
+    let new = Domain::new();
+    let mut thread = Thread::new(&old);
+    thread.set_max_local_retired(4);
+    for id in 0..3 {
+        unsafe { thread.retire(drops.node(id)) };
+        let node = Box::into_raw(Box::new(Node(DropNode { id: 3 + id, drops })));
+        assert!(unsafe { thread.try_unlink(UnlinkNode(node), &[]) });
+    }
+    // Invalidating the unlinked pointers exceeds `max_local_retired`, but nothing is flushed.
+    let retireds = thread.detach();
+    assert_eq!(retireds.len(), 6);
+    assert_eq!(old.num_garbages(), 0);
+
+    let mut thread = unsafe { Thread::adopt(&new, retireds) };
+    assert_eq!(thread.force_reclaim(), 6);
+    assert_eq!(drops.count(), 6);
+}
+
+#[test]
+fn tag_bits() {
+    let domain = Domain::with_config(DomainConfig {
+        tag_bits: 1,
+        ..DomainConfig::new()
+    });
+    let mut thread = Thread::new(&domain);
+    let ptr = Box::into_raw(Box::new(0u64));
+    let mut hp = HazardPointer::new(&mut thread);
+    hp.protect_raw(tagged(ptr, 1));
+    unsafe { thread.retire(ptr) };
+    assert_eq!(thread.force_reclaim(), 0);
+    assert_eq!(domain.num_garbages(), 1);
+
+    drop(hp);
+    assert_eq!(thread.force_reclaim(), 1);
+}
+
+#[test]
+#[should_panic(expected = "`tag_bits` of a domain can't be changed")]
+fn apply_tag_bits() {
+    let domain = Domain::new();
+    domain.apply_config(DomainConfig {
+        tag_bits: 1,
+        ..domain.config_snapshot()
+    });
+}
+
+#[test]
+fn reclamation_callbacks() {
+    use core::sync::atomic::AtomicUsize;
+
+    static RETIRED: AtomicUsize = AtomicUsize::new(0);
+    static FREED: AtomicUsize = AtomicUsize::new(0);
+    static RETAINED: AtomicUsize = AtomicUsize::new(0);
+
+    let drops = Drops::new();
+    let domain = Domain::with_config(DomainConfig {
+        on_retire: Some(|count| {
+            RETIRED.fetch_add(count, Relaxed);
+        }),
+        on_reclaim: Some(|freed, retained| {
+            FREED.fetch_add(freed, Relaxed);
+            RETAINED.store(retained, Relaxed);
+        }),
+        ..DomainConfig::new()
+    });
+    let mut thread = Thread::new(&domain);
+    let guarded = drops.node(0);
+    let mut hp = HazardPointer::new(&mut thread);
+    hp.protect_raw(guarded);
+    unsafe { thread.retire(guarded) };
+    for i in 1..10 {
+        unsafe { thread.retire(drops.node(i)) };
+    }
+    thread.force_reclaim();
+    assert_eq!(RETIRED.load(Relaxed), 10);
+    assert_eq!(FREED.load(Relaxed), 9);
+    assert_eq!(drops.count(), 9);
+    assert_eq!(RETAINED.load(Relaxed), 1);
+
+    drop(hp);
+    thread.force_reclaim();
+    assert_eq!(FREED.load(Relaxed), 10);
+    assert_eq!(drops.count(), 10);
+    assert_eq!(RETAINED.load(Relaxed), 0);
+}
+
+#[test]
+fn retired_drop() {
+    // A node owning a heap allocation.
+    let drops = Drops::new();
+    let node = |id: usize| Box::into_raw(Box::new((DropNode { id, drops }, id.to_string())));
+
+    let domain = Domain::new();
+    let mut thread = Thread::new(&domain);
+    unsafe { thread.retire(node(0)) };
+    assert_eq!(thread.force_reclaim(), 1);
+    assert_eq!(drops.ids(), [0]);
+
+    let retireds = vec![Retired::new(node(1)), Retired::new_no_drop(node(2))];
+    drop(thread);
+    let mut thread = unsafe { Thread::adopt(&domain, retireds) };
+    assert_eq!(thread.force_reclaim(), 2);
+    assert_eq!(drops.ids(), [0, 1]);
+}
+
+#[test]
+fn seq_cst_barrier() {
+    const THREADS: usize = 4;
+    const ITERS: usize = 1000;
+
+    let domain = Domain::with_config(DomainConfig {
+        barrier_strategy: BarrierStrategy::SeqCst,
+        ..DomainConfig::new()
+    });
+    let src = AtomicPtr::new(Box::into_raw(Box::new(0usize)));
+    scope(|s| {
+        for _ in 0..THREADS {
+            s.spawn(|| {
+                let mut thread = Thread::new(&domain);
+                for i in 0..ITERS {
+                    let mut hp = HazardPointer::new(&mut thread);
+                    let ptr = hp.protect(&src);
+                    let _ = unsafe { *ptr };
+                    drop(hp);
+                    let old = src.swap(Box::into_raw(Box::new(i)), AcqRel);
+                    unsafe { thread.retire(old) };
+                }
+            });
+        }
+    });
+    let mut thread = Thread::new(&domain);
+    thread.force_reclaim();
+    assert_eq!(domain.num_garbages(), 0);
+    assert!(domain.stats().reclamation_barriers > 0);
+    drop(thread);
+    drop(unsafe { Box::from_raw(src.into_inner()) });
+}
+
+#[test]
+fn reclaim_all() {
+    let domain = Domain::with_config(DomainConfig {
+        two_epoch_grace: true,
+        ..DomainConfig::new()
+    });
+    // No thread record is registered for the reclamation.
+    unsafe { domain.retire_global(Box::into_raw(Box::new(0))) };
+    assert_eq!(domain.reclaim_all(), 1);
+    assert_eq!(domain.stats().hazard_bytes, 0);
+
+    scope(|s| {
+        for _ in 0..4 {
+            s.spawn(|| {
+                let mut thread = Thread::new(&domain);
+                for i in 0..100 {
+                    unsafe { thread.retire(Box::into_raw(Box::new(i))) };
+                }
+            });
+        }
+    });
+    assert_eq!(domain.attached_threads(), 0);
+    assert_eq!(domain.num_garbages(), 400);
+    assert_eq!(domain.reclaim_all(), 400);
+    assert_eq!(domain.num_garbages(), 0);
+
+    let mut thread = Thread::new(&domain);
+    let guarded = Box::into_raw(Box::new(0));
+    let mut hp = HazardPointer::new(&mut thread);
+    hp.protect_raw(guarded);
+    unsafe { domain.retire_global(guarded) };
+    assert_eq!(domain.reclaim_all(), 0);
+    assert_eq!(domain.num_garbages(), 1);
+    drop(hp);
+    assert_eq!(domain.reclaim_all(), 1);
+    assert_eq!(domain.num_garbages(), 0);
+}
+
+#[test]
+fn protect_pair() {
+    const ROTATIONS: usize = 10000;
+
+    let domain = Domain::new();
+    let a = AtomicPtr::new(Box::into_raw(Box::new(0usize)));
+    let b = AtomicPtr::new(Box::into_raw(Box::new(0usize)));
+    scope(|s| {
+        // Each rotation replaces `b` and then `a` with nodes of the next generation, so `b` is at
+        // most one generation ahead of `a` at any moment.
+        s.spawn(|| {
+            let mut thread = Thread::new(&domain);
+            for gen in 1..=ROTATIONS {
+                let old_b = b.swap(Box::into_raw(Box::new(gen)), AcqRel);
+                let old_a = a.swap(Box::into_raw(Box::new(gen)), AcqRel);
+                unsafe {
+                    thread.retire(old_b);
+                    thread.retire(old_a);
+                }
+            }
+        });
+        s.spawn(|| {
+            let mut thread = Thread::new(&domain);
+            loop {
+                let (hp_a, hp_b, ptr_a, ptr_b) = thread.protect_pair(&a, &b);
+                let (gen_a, gen_b) = unsafe { (*ptr_a, *ptr_b) };
+                assert!(gen_b == gen_a || gen_b == gen_a + 1);
+                drop((hp_a, hp_b));
+                assert_eq!(thread.active_hazards(), 0);
+                if gen_a == ROTATIONS {
+                    break;
+                }
+            }
+        });
+    });
+    unsafe {
+        drop(Box::from_raw(a.into_inner()));
+        drop(Box::from_raw(b.into_inner()));
+    }
+}
+
+#[cfg(feature = "metrics")]
+#[test]
+fn slot_reuse_distances() {
+    let domain = Domain::new();
+    let mut thread = Thread::new(&domain);
+
+    // Fresh slots have no reuse distance.
+    let hp1 = HazardPointer::new(&mut thread);
+    let hp2 = HazardPointer::new(&mut thread);
+    assert_eq!(thread.slot_reuse_distances().iter().sum::<usize>(), 0);
+
+    // The released slots are reused in LIFO order.
+    drop(hp1);
+    drop(hp2);
+    let hp = HazardPointer::new(&mut thread);
+    assert_eq!(thread.slot_reuse_distances()[0], 1);
+    let _hp = HazardPointer::new(&mut thread);
+    assert_eq!(thread.slot_reuse_distances()[1], 1);
+    drop(hp);
+}
+
+#[cfg(feature = "retire-notify")]
+#[test]
+fn protect_with_notify() {
+    use core::sync::atomic::AtomicBool;
+    use std::sync::Arc;
+
+    let domain = Domain::new();
+    let mut thread = Thread::new(&domain);
+    let src = AtomicPtr::new(Box::into_raw(Box::new(1usize)));
+    let notified = Arc::new(AtomicBool::new(false));
+
+    let mut hp = HazardPointer::new(&mut thread);
+    let ptr = {
+        let notified = notified.clone();
+        hp.protect_with_notify(&src, move || notified.store(true, Relaxed))
+    };
+    assert_eq!(unsafe { *ptr }, 1);
+    drop(hp);
+
+    unsafe { thread.retire(Box::into_raw(Box::new(2usize))) };
+    assert!(!notified.load(Relaxed));
+    assert_eq!(src.swap(core::ptr::null_mut(), AcqRel), ptr);
+    unsafe { thread.retire(ptr) };
+    assert!(notified.load(Relaxed));
+
+    let mut hp = HazardPointer::new(&mut thread);
+    assert!(hp.protect_with_notify(&src, || unreachable!()).is_null());
+}
+
+#[cfg(feature = "debug-checks")]
+#[test]
+#[should_panic(expected = "is not invalidated")]
+fn broken_invalidate() {
+    struct Node;
+
+    impl Invalidate for Node {
+        fn invalidate(&self) {}
+
+        fn is_invalidated(&self) -> bool {
+            false
+        }
+    }
+
+    struct UnlinkNode(*mut Node);
+
+    impl Unlink<Node> for UnlinkNode {
+        fn do_unlink(&self) -> Result<Vec<*mut Node>, ()> {
+            Ok(vec![self.0])
+        }
+    }
+
+    // Leaked, because the panicking `Thread` doesn't release its record.
+    let domain = Box::leak(Box::new(Domain::new()));
+    let mut thread = Thread::new(domain);
+    let node = Box::into_raw(Box::new(Node));
+    unsafe { thread.try_unlink(UnlinkNode(node), &[]) };
+    drop(thread);
+}
+
 mod stack {
     use core::mem::ManuallyDrop;
     use core::ptr;
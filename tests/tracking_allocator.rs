@@ -0,0 +1,63 @@
+#![cfg(feature = "debug-checks")]
+
+use std::alloc::System;
+
+use hp_pp::*;
+
+#[global_allocator]
+static ALLOCATOR: TrackingAllocator = TrackingAllocator::new(System);
+
+fn tracked_domain() -> Domain {
+    let mut domain = Domain::new();
+    domain.set_allocation_tracker(&ALLOCATOR);
+    domain
+}
+
+#[test]
+fn retire_allocated() {
+    let domain = tracked_domain();
+    let mut thread = Thread::new(&domain);
+    for i in 0..256 {
+        unsafe { thread.retire(Box::into_raw(Box::new(i))) };
+    }
+}
+
+#[test]
+#[should_panic(expected = "not allocated by the tracked allocator")]
+fn retire_stack_pointer() {
+    let domain = tracked_domain();
+    let mut thread = Thread::new(&domain);
+    let mut on_stack = 0usize;
+    unsafe { thread.retire(&mut on_stack as *mut usize) };
+}
+
+#[test]
+#[should_panic(expected = "not allocated by the tracked allocator")]
+fn retire_freed_pointer() {
+    let domain = tracked_domain();
+    let mut thread = Thread::new(&domain);
+    let ptr = Box::into_raw(Box::new([0u64; 4]));
+    drop(unsafe { Box::from_raw(ptr) });
+    unsafe { thread.retire(ptr) };
+}
+
+#[test]
+fn scratch_domain_leak() {
+    let domain = tracked_domain();
+    let mut thread = Thread::new(&domain);
+    let mut scratch = || {
+        thread.with_scratch_domain(|scratch| {
+            for i in 0..16 {
+                unsafe { scratch.retire(Box::into_raw(Box::new(i))) };
+            }
+        })
+    };
+    scratch();
+    let live = ALLOCATOR.live_bytes();
+    for _ in 0..1000 {
+        scratch();
+    }
+    // Each scratch domain allocates a record and a hazard array of over 512 bytes. Allow for the
+    // allocations of the tests running alongside.
+    assert!(ALLOCATOR.live_bytes() < live + 64 * 1024);
+}